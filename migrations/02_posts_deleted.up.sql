@@ -0,0 +1,3 @@
+ALTER TABLE posts
+    ADD COLUMN deleted_at timestamp(0) with time zone,
+    ADD COLUMN deleted_by integer;
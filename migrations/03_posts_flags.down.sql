@@ -0,0 +1,4 @@
+ALTER TABLE posts
+    DROP COLUMN IF EXISTS sticky,
+    DROP COLUMN IF EXISTS locked,
+    DROP COLUMN IF EXISTS autosage;
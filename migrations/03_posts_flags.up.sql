@@ -0,0 +1,4 @@
+ALTER TABLE posts
+    ADD COLUMN sticky boolean DEFAULT false NOT NULL,
+    ADD COLUMN locked boolean DEFAULT false NOT NULL,
+    ADD COLUMN autosage boolean DEFAULT false NOT NULL;
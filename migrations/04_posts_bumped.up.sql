@@ -0,0 +1,7 @@
+ALTER TABLE posts
+    ADD COLUMN bumped timestamp(0) with time zone DEFAULT now() NOT NULL;
+
+UPDATE posts p SET bumped = coalesce(
+    (SELECT max(c.created) FROM posts c WHERE c.parent = p.id),
+    p.created
+) WHERE p.parent IS NULL;
@@ -0,0 +1,3 @@
+ALTER TABLE boards
+    DROP COLUMN IF EXISTS threads_per_page,
+    DROP COLUMN IF EXISTS max_pages;
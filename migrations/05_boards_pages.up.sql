@@ -0,0 +1,5 @@
+ALTER TABLE boards
+    ADD COLUMN threads_per_page integer,
+    ADD COLUMN max_pages integer,
+    ADD CONSTRAINT boards_threads_per_page_check CHECK (threads_per_page > 0),
+    ADD CONSTRAINT boards_max_pages_check CHECK (max_pages > 0);
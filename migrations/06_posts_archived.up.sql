@@ -0,0 +1,2 @@
+ALTER TABLE posts
+    ADD COLUMN archived_at timestamp(0) with time zone;
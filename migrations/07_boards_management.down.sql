@@ -0,0 +1,3 @@
+ALTER TABLE boards
+    DROP CONSTRAINT IF EXISTS boards_name_key,
+    DROP COLUMN IF EXISTS posts;
@@ -0,0 +1,3 @@
+ALTER TABLE boards
+    ADD COLUMN posts integer DEFAULT 0 NOT NULL,
+    ADD CONSTRAINT boards_name_key UNIQUE (name);
@@ -0,0 +1,7 @@
+ALTER TABLE boards
+    DROP COLUMN IF EXISTS max_file_size,
+    DROP COLUMN IF EXISTS allowed_mimes,
+    DROP COLUMN IF EXISTS max_files,
+    DROP COLUMN IF EXISTS default_name,
+    DROP COLUMN IF EXISTS text_only,
+    DROP COLUMN IF EXISTS nsfw;
@@ -0,0 +1,11 @@
+ALTER TABLE boards
+    ADD COLUMN max_file_size bigint,
+    ADD COLUMN allowed_mimes text [],
+    ADD COLUMN max_files integer,
+    ADD COLUMN default_name text,
+    ADD COLUMN text_only boolean DEFAULT false NOT NULL,
+    ADD COLUMN nsfw boolean DEFAULT false NOT NULL,
+
+    ADD CONSTRAINT boards_max_file_size_check CHECK (max_file_size > 0),
+    ADD CONSTRAINT boards_max_files_check CHECK (max_files >= 0),
+    ADD CONSTRAINT boards_default_name_check CHECK (length(default_name) < 64);
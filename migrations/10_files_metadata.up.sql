@@ -0,0 +1,17 @@
+DROP TABLE IF EXISTS files;
+
+CREATE TABLE files (
+    id SERIAL NOT NULL,
+    name text UNIQUE NOT NULL,
+    hash text UNIQUE NOT NULL,
+    mime text NOT NULL,
+    size bigint NOT NULL,
+    width integer,
+    height integer,
+    filename text,
+    thumbnail text,
+    created timestamp(0) with time zone DEFAULT now() NOT NULL,
+
+    CONSTRAINT files_name_check CHECK (length(name) > 20),
+    CONSTRAINT files_filename_check CHECK (length(filename) < 256)
+);
@@ -0,0 +1,4 @@
+DROP TABLE IF EXISTS banned_files;
+
+ALTER TABLE files
+    DROP COLUMN IF EXISTS phash;
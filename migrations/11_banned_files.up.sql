@@ -0,0 +1,13 @@
+ALTER TABLE files
+    ADD COLUMN phash bigint;
+
+CREATE TABLE banned_files (
+    id SERIAL NOT NULL,
+    hash text UNIQUE NOT NULL,
+    phash bigint,
+    reason text,
+    banned_by integer,
+    created timestamp(0) with time zone DEFAULT now() NOT NULL,
+
+    CONSTRAINT banned_files_reason_check CHECK (length(reason) < 256)
+);
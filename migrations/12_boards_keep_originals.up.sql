@@ -0,0 +1,2 @@
+ALTER TABLE boards
+    ADD COLUMN keep_originals boolean DEFAULT false NOT NULL;
@@ -0,0 +1,4 @@
+ALTER TABLE posts
+    DROP COLUMN IF EXISTS filenames,
+    DROP COLUMN IF EXISTS sizes,
+    DROP COLUMN IF EXISTS dimensions;
@@ -0,0 +1,4 @@
+ALTER TABLE posts
+    ADD COLUMN filenames text[],
+    ADD COLUMN sizes bigint[],
+    ADD COLUMN dimensions text[];
@@ -0,0 +1,5 @@
+ALTER TABLE posts
+    DROP COLUMN IF EXISTS durations;
+
+ALTER TABLE files
+    DROP COLUMN IF EXISTS duration;
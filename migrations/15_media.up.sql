@@ -0,0 +1,5 @@
+ALTER TABLE files
+    ADD COLUMN duration real;
+
+ALTER TABLE posts
+    ADD COLUMN durations real[];
@@ -0,0 +1,6 @@
+DELETE FROM captchas;
+
+ALTER TABLE captchas
+    DROP CONSTRAINT captchas_pkey,
+    DROP CONSTRAINT captchas_solution_check,
+    ADD CONSTRAINT captchas_solution_check CHECK (length(solution) = 6);
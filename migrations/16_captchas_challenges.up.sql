@@ -0,0 +1,6 @@
+DELETE FROM captchas;
+
+ALTER TABLE captchas
+    DROP CONSTRAINT captchas_solution_check,
+    ADD CONSTRAINT captchas_solution_check CHECK (length(solution) BETWEEN 1 AND 16),
+    ADD PRIMARY KEY (id);
@@ -0,0 +1,7 @@
+DROP INDEX IF EXISTS posts_ip_created_idx;
+
+ALTER TABLE posts
+    DROP COLUMN IF EXISTS ip;
+
+ALTER TABLE boards
+    DROP COLUMN IF EXISTS captcha_policy;
@@ -0,0 +1,8 @@
+ALTER TABLE boards
+    ADD COLUMN captcha_policy text
+        CHECK (captcha_policy IN ('always', 'threads', 'first_post', 'never'));
+
+ALTER TABLE posts
+    ADD COLUMN ip text;
+
+CREATE INDEX posts_ip_created_idx ON posts (ip, created);
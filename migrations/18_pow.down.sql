@@ -0,0 +1,4 @@
+DROP TABLE IF EXISTS pow_challenges;
+
+ALTER TABLE boards
+    DROP COLUMN IF EXISTS pow_difficulty;
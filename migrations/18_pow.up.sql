@@ -0,0 +1,9 @@
+ALTER TABLE boards
+    ADD COLUMN pow_difficulty integer CHECK (pow_difficulty BETWEEN 0 AND 32);
+
+CREATE TABLE pow_challenges (
+    nonce text PRIMARY KEY,
+    expires timestamp(0) with time zone DEFAULT now() NOT NULL,
+
+    CONSTRAINT pow_challenges_nonce_check CHECK (length(nonce) = 40)
+);
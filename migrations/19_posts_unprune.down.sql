@@ -0,0 +1 @@
+-- Archived threads can't be told apart from formerly pruned ones, nothing to undo.
@@ -0,0 +1,11 @@
+-- Threads hidden by the old page pruning look like deletions without a moderator, they and
+-- their replies belong in the archive instead.
+UPDATE posts SET deleted_at = NULL
+WHERE deleted_at IS NOT NULL AND deleted_by IS NULL
+AND parent IN (
+    SELECT id FROM posts
+    WHERE parent IS NULL AND deleted_at IS NOT NULL AND deleted_by IS NULL
+);
+
+UPDATE posts SET archived_at = deleted_at, deleted_at = NULL
+WHERE parent IS NULL AND deleted_at IS NOT NULL AND deleted_by IS NULL;
@@ -0,0 +1,6 @@
+-- Roles used to be stored as the debug output of an `Option<Role>`.
+UPDATE users SET role = substring(role FROM '^some\((.*)\)$') WHERE role LIKE 'some(%)';
+UPDATE users SET role = 'user' WHERE role = 'none';
+
+ALTER TABLE users
+    ADD CONSTRAINT users_role_check CHECK (role IN ('admin', 'moderator', 'volunteer', 'user'));
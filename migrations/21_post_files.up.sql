@@ -0,0 +1,18 @@
+CREATE TABLE post_files (
+    post integer NOT NULL,
+    file text NOT NULL REFERENCES files (hash),
+    position smallint NOT NULL,
+
+    PRIMARY KEY (post, position)
+);
+
+CREATE INDEX post_files_file_idx ON post_files (file);
+
+INSERT INTO post_files (post, file, position)
+SELECT p.id, f.hash, a.position - 1
+FROM posts p
+CROSS JOIN LATERAL unnest(p.files) WITH ORDINALITY AS a(name, position)
+JOIN files f ON f.name = a.name;
+
+-- rows of files that never made it into a post, e.g. because of a wrong captcha
+DELETE FROM files WHERE NOT EXISTS (SELECT 1 FROM post_files WHERE file = files.hash);
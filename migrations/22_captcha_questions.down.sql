@@ -0,0 +1,6 @@
+DELETE FROM captchas WHERE ignore_case OR length(solution) > 16;
+
+ALTER TABLE captchas
+    DROP COLUMN IF EXISTS ignore_case,
+    DROP CONSTRAINT captchas_solution_check,
+    ADD CONSTRAINT captchas_solution_check CHECK (length(solution) BETWEEN 1 AND 16);
@@ -0,0 +1,4 @@
+ALTER TABLE captchas
+    ADD COLUMN ignore_case boolean NOT NULL DEFAULT false,
+    DROP CONSTRAINT captchas_solution_check,
+    ADD CONSTRAINT captchas_solution_check CHECK (length(solution) BETWEEN 1 AND 256);
@@ -0,0 +1,37 @@
+DROP VIEW IF EXISTS post_attachments;
+
+ALTER TABLE posts
+    ADD COLUMN files text[],
+    ADD COLUMN thumbnails text[],
+    ADD COLUMN filenames text[],
+    ADD COLUMN sizes bigint[],
+    ADD COLUMN dimensions text[],
+    ADD COLUMN durations real[],
+    ADD COLUMN spoilers boolean[];
+
+UPDATE posts p
+SET files = a.files,
+    thumbnails = a.thumbnails,
+    filenames = a.filenames,
+    sizes = a.sizes,
+    dimensions = a.dimensions,
+    durations = a.durations,
+    spoilers = a.spoilers
+FROM (
+    SELECT pf.post,
+           array_agg(f.name ORDER BY pf.position) AS files,
+           array_agg(COALESCE(f.thumbnail, '') ORDER BY pf.position) AS thumbnails,
+           array_agg(COALESCE(pf.filename, '') ORDER BY pf.position) AS filenames,
+           array_agg(f.size ORDER BY pf.position) AS sizes,
+           array_agg(COALESCE(f.width || 'x' || f.height, '') ORDER BY pf.position) AS dimensions,
+           array_agg(COALESCE(f.duration, 0) ORDER BY pf.position) AS durations,
+           array_agg(pf.spoiler ORDER BY pf.position) AS spoilers
+    FROM post_files pf
+    JOIN files f ON f.hash = pf.file
+    GROUP BY pf.post
+) a
+WHERE p.id = a.post;
+
+ALTER TABLE post_files
+    DROP COLUMN IF EXISTS filename,
+    DROP COLUMN IF EXISTS spoiler;
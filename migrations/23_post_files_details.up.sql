@@ -0,0 +1,36 @@
+ALTER TABLE post_files
+    ADD COLUMN filename text,
+    ADD COLUMN spoiler boolean NOT NULL DEFAULT false,
+    ADD CONSTRAINT post_files_filename_check CHECK (length(filename) < 256);
+
+UPDATE post_files pf
+SET filename = NULLIF(p.filenames[pf.position + 1], ''),
+    spoiler = COALESCE(p.spoilers[pf.position + 1], false)
+FROM posts p
+WHERE p.id = pf.post;
+
+ALTER TABLE posts
+    DROP COLUMN IF EXISTS files,
+    DROP COLUMN IF EXISTS thumbnails,
+    DROP COLUMN IF EXISTS filenames,
+    DROP COLUMN IF EXISTS sizes,
+    DROP COLUMN IF EXISTS dimensions,
+    DROP COLUMN IF EXISTS durations,
+    DROP COLUMN IF EXISTS spoilers;
+
+-- one row per post with files, in the order they were attached
+CREATE VIEW post_attachments AS
+SELECT pf.post,
+       json_agg(json_build_object(
+           'name', f.name,
+           'thumbnail', f.thumbnail,
+           'filename', pf.filename,
+           'size', f.size,
+           'width', f.width,
+           'height', f.height,
+           'duration', f.duration,
+           'spoiler', pf.spoiler
+       ) ORDER BY pf.position) AS files
+FROM post_files pf
+JOIN files f ON f.hash = pf.file
+GROUP BY pf.post;
@@ -2,11 +2,12 @@ use crate::utils::{
     captcha::CaptchaService,
     data::App,
     fake::ImagePool,
-    helpers::{graceful_shutdown, read_config},
+    helpers::{archive_threads, expire_captchas, graceful_shutdown, read_config},
+    storage::open_storage,
 };
 
 use axum_server::Handle;
-use color_eyre::eyre::Result;
+use color_eyre::eyre::{Result, WrapErr};
 use color_eyre::Report;
 use std::{net::SocketAddr, sync::Arc};
 use tokio::sync::RwLock;
@@ -29,7 +30,8 @@ async fn main() -> Result<(), Report> {
 
     let config = read_config()
         .await
-        .expect("error parsing configuration file");
+        .wrap_err("error parsing configuration file")?;
+    tracing::info!("upload limit set to {}", config.security.upload_limit);
     let dsn = format!(
         "postgresql://{}:{}@{}",
         config.psql.username, config.psql.password, config.psql.address,
@@ -39,16 +41,23 @@ async fn main() -> Result<(), Report> {
         .expect("failed to connect to database");
 
     let models = PoolModel { pool: pool.clone() };
+    models
+        .reconcile_boards(&config.security.boards, config.security.prune_boards)
+        .await?;
     let boards = models.get_boards().await;
 
-    let app = Arc::new(App::new(config, models, boards));
-    let cs = Arc::new(RwLock::new(CaptchaService::new(10).await));
+    let storage = open_storage(&config).wrap_err("error opening file storage")?;
+    let app = Arc::new(App::new(config, models, boards, storage.clone()));
+    tokio::spawn(archive_threads(app.clone()));
+    tokio::spawn(expire_captchas(app.clone()));
+
+    let cs = Arc::new(RwLock::new(CaptchaService::new(&app.config.captcha).await));
     let router = routes(app, cs);
 
     let handle = Handle::new();
     tokio::spawn(graceful_shutdown(handle.clone(), pool.clone()));
 
-    let mut ip = ImagePool::new(&pool, 20).await?;
+    let mut ip = ImagePool::new(&pool, storage, 20).await?;
     ip.truncate().await?;
     ip.mock(10, 5).await?;
 
@@ -58,7 +67,7 @@ async fn main() -> Result<(), Report> {
 
     axum_server::bind(addr)
         .handle(handle)
-        .serve(router.into_make_service())
+        .serve(router.into_make_service_with_connect_info::<SocketAddr>())
         .await?;
 
     Ok(())
@@ -1,20 +1,37 @@
-
-use std::thread::sleep;
 use std::{sync::Arc, time::Duration};
 
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
 use tokio::{sync::RwLock, task};
 
 use captcha_a::{Captcha, CaptchaBuilder, Font};
-use rand::{
-    distributions::{Alphanumeric, DistString},
-    seq::SliceRandom,
-};
+use rand::{seq::SliceRandom, Rng};
+
+use super::config::{CaptchaConfig, CaptchaQuestion};
+
+/// Characters a solution is made of, without the ones that are easily confused like 0 and O.
+const SOURCE: &str = "ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnpqrstuvwxyz23456789";
 
 #[derive(Clone, Debug)]
 pub struct MyCaptcha(pub String, pub Vec<u8>);
 
-#[derive(Clone, Debug)]
-pub struct CaptchaService(Arc<RwLock<Vec<MyCaptcha>>>);
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct CaptchaStats {
+    pub pool_size: usize,
+    pub refreshes: u64,
+    pub refreshed_at: Option<DateTime<Utc>>,
+    /// How long generating the last batch took.
+    pub refresh_ms: u128,
+}
+
+struct Pool {
+    captchas: Vec<MyCaptcha>,
+    stats: CaptchaStats,
+}
+
+#[derive(Clone)]
+pub struct CaptchaService(Arc<RwLock<Pool>>);
 
 impl From<Captcha> for MyCaptcha {
     fn from(c: Captcha) -> Self {
@@ -23,35 +40,70 @@ impl From<Captcha> for MyCaptcha {
 }
 
 impl CaptchaService {
-    pub async fn new(amount: usize) -> CaptchaService {
-        let font: Font =
+    /// Fills the pool and keeps replacing it in the background every `config.interval` seconds.
+    pub async fn new(config: &CaptchaConfig) -> CaptchaService {
+        let font: Font<'static> =
             Font::try_from_bytes(include_bytes!("../../windows_command_prompt.ttf")).unwrap();
 
-        let v = Arc::new(RwLock::new(generate(amount, font.clone())));
-        let w = v.clone();
+        let pool = Arc::new(RwLock::new(Pool {
+            captchas: vec![],
+            stats: CaptchaStats::default(),
+        }));
+        let service = CaptchaService(pool);
+        service.refresh(config.pool_size.get() as usize, &font).await;
 
-        task::spawn_blocking(|| async move {
-            loop {
-                sleep(Duration::from_secs(1));
-                let mut v = w.write().await;
+        let refresher = service.clone();
+        let size = config.pool_size.get() as usize;
+        let period = Duration::from_secs(config.interval.get());
+        task::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            // the first tick completes immediately and the pool was just filled
+            interval.tick().await;
 
-                *v = generate(5, font.clone());
+            loop {
+                interval.tick().await;
+                refresher.refresh(size, &font).await;
             }
         });
 
-        CaptchaService(v)
+        service
+    }
+
+    async fn refresh(&self, size: usize, font: &Font<'static>) {
+        let start = std::time::Instant::now();
+        let font = font.clone();
+        let captchas = match task::spawn_blocking(move || generate(size, font)).await {
+            Ok(captchas) => captchas,
+            Err(e) => {
+                tracing::error!("failed to generate captchas: {}", e);
+                return;
+            }
+        };
+
+        let mut pool = self.0.write().await;
+        pool.captchas = captchas;
+        pool.stats = CaptchaStats {
+            pool_size: pool.captchas.len(),
+            refreshes: pool.stats.refreshes + 1,
+            refreshed_at: Some(Utc::now()),
+            refresh_ms: start.elapsed().as_millis(),
+        };
+    }
+
+    pub async fn stats(&self) -> CaptchaStats {
+        self.0.read().await.stats.clone()
     }
 }
 
+/// Every captcha draws its own solution from `SOURCE`.
 pub fn generate(i: usize, font: Font<'static>) -> Vec<MyCaptcha> {
-    let source = Alphanumeric.sample_string(&mut rand::thread_rng(), 6);
     (0..i)
         .map(|_| {
             let builder = CaptchaBuilder {
                 width: 120,
                 height: 40,
                 length: 4,
-                source: source.clone(),
+                source: SOURCE.to_owned(),
                 fonts: &[font.clone()],
                 ..Default::default()
             };
@@ -60,9 +112,105 @@ pub fn generate(i: usize, font: Font<'static>) -> Vec<MyCaptcha> {
         .collect()
 }
 
+/// A challenge that can be read by a screen reader instead of looking at an image.
+#[derive(Clone, Debug)]
+pub struct TextCaptcha {
+    pub question: String,
+    /// Lowercase, answers are compared the same way.
+    pub solution: String,
+}
+
+impl TextCaptcha {
+    /// Picks one of the configured questions, `None` when there aren't any.
+    pub fn choose(questions: &[CaptchaQuestion], rng: &mut impl Rng) -> Option<TextCaptcha> {
+        questions.choose(rng).map(|x| TextCaptcha {
+            question: x.question.clone(),
+            solution: x.answer.trim().to_lowercase(),
+        })
+    }
+}
+
+/// A hashcash style challenge, posters look for a counter so that `SHA-256(nonce:counter)`
+/// starts with `difficulty` zero bits and submit `nonce:counter` instead of a captcha.
+#[derive(Clone, Debug, Serialize)]
+pub struct PowChallenge {
+    pub nonce: String,
+    pub difficulty: u32,
+}
+
+pub fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+pub fn verify_pow(nonce: &str, counter: &str, difficulty: u32) -> bool {
+    let hash = Sha256::digest(format!("{}:{}", nonce, counter));
+    leading_zero_bits(&hash) >= difficulty
+}
+
 impl CaptchaService {
     pub async fn recv(&self) -> MyCaptcha {
         let v = self.0.read().await;
-        v.choose(&mut rand::thread_rng()).unwrap().to_owned()
+        v.captchas.choose(&mut rand::thread_rng()).unwrap().to_owned()
     }
 }
+
+#[tokio::test]
+async fn test_refresh() {
+    // long enough for the background refresh to stay out of the way
+    let config = CaptchaConfig {
+        pool_size: crate::utils::config::NonZero::try_from(3).unwrap(),
+        interval: crate::utils::config::NonZero::try_from(3600).unwrap(),
+        ..Default::default()
+    };
+    let service = CaptchaService::new(&config).await;
+
+    let stats = service.stats().await;
+    assert_eq!(stats.pool_size, 3);
+    assert_eq!(stats.refreshes, 1);
+
+    let font = Font::try_from_bytes(include_bytes!("../../windows_command_prompt.ttf")).unwrap();
+    service.refresh(5, &font).await;
+    let stats = service.stats().await;
+    assert_eq!((stats.pool_size, stats.refreshes), (5, 2));
+
+    let phrases: Vec<_> = generate(20, font).into_iter().map(|x| x.0).collect();
+    assert!(phrases.iter().any(|x| x != &phrases[0]));
+}
+
+#[test]
+fn test_text_captcha() {
+    let questions = vec![CaptchaQuestion {
+        question: "What is the name of this site?".to_owned(),
+        answer: " Tokichan ".to_owned(),
+    }];
+    let mut rng = rand::thread_rng();
+
+    let captcha = TextCaptcha::choose(&questions, &mut rng).unwrap();
+    assert_eq!(captcha.question, "What is the name of this site?");
+    assert_eq!(captcha.solution, "tokichan");
+    assert!(TextCaptcha::choose(&[], &mut rng).is_none());
+}
+
+#[test]
+fn test_verify_pow() {
+    assert_eq!(leading_zero_bits(&[0xff]), 0);
+    assert_eq!(leading_zero_bits(&[0x00, 0x00, 0x10, 0x00]), 19);
+    assert_eq!(leading_zero_bits(&[0x00, 0x00]), 16);
+
+    let nonce = "0123456789abcdef0123456789abcdef01234567";
+    let counter = (0u32..)
+        .map(|n| n.to_string())
+        .find(|n| verify_pow(nonce, n, 10))
+        .unwrap();
+    let hash = Sha256::digest(format!("{}:{}", nonce, counter));
+    assert_eq!(&hash[..1], &[0]);
+    assert!(hash[1] < 0x40);
+    assert!(!verify_pow("another nonce", &counter, 32));
+}
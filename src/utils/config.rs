@@ -1,13 +1,45 @@
-use color_eyre::Report;
+use std::{fmt, path::PathBuf};
+
 use regex::Regex;
 use serde_derive::Deserialize;
-
-use tracing::{event, Level};
+use thiserror::Error;
 
 #[derive(Deserialize)]
 pub struct Config {
+    pub main: Main,
     pub psql: Psql,
     pub security: Security,
+    #[serde(default)]
+    pub board: BoardConfig,
+    #[serde(default)]
+    pub archive: ArchiveConfig,
+    #[serde(default)]
+    pub storage: StorageConfig,
+    #[serde(default)]
+    pub captcha: CaptchaConfig,
+}
+
+#[derive(Deserialize)]
+pub struct Main {
+    /// Where uploads are kept when using the local storage backend.
+    pub file_directory: PathBuf,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum StorageConfig {
+    #[default]
+    Local,
+    S3(S3Config),
+}
+
+#[derive(Deserialize)]
+pub struct S3Config {
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
 }
 
 #[derive(Deserialize)]
@@ -17,29 +49,293 @@ pub struct Psql {
     pub address: String,
 }
 
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct BoardConfig {
+    pub bump_limit: i64,
+    pub threads_per_page: i64,
+    pub max_pages: i64,
+}
+
+impl Default for BoardConfig {
+    fn default() -> Self {
+        Self {
+            bump_limit: 300,
+            threads_per_page: 15,
+            max_pages: 10,
+        }
+    }
+}
+
+/// Durations are expressed in seconds.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct ArchiveConfig {
+    pub interval: NonZero,
+    /// New threads archive the ones they push off the last page right away, the background
+    /// task only catches boards whose capacity shrank and waits this long before doing so.
+    pub min_age: u64,
+    /// Addresses of posts older than this are forgotten by the same task, it has to stay above
+    /// the captcha `window` for `first_post` to recognize anyone.
+    pub ip_retention: u64,
+    /// Threads listed on a page of a board's archive.
+    pub per_page: NonZero,
+}
+
+impl Default for ArchiveConfig {
+    fn default() -> Self {
+        Self {
+            interval: NonZero(300),
+            min_age: 3600,
+            ip_retention: 2592000,
+            per_page: NonZero(100),
+        }
+    }
+}
+
+/// Durations are expressed in seconds.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct CaptchaConfig {
+    /// How long an issued challenge can be answered.
+    pub lifetime: NonZero,
+    /// Amount of pre-rendered captchas challenges are drawn from.
+    pub pool_size: NonZero,
+    /// How often the pool is replaced with freshly rendered captchas.
+    pub interval: NonZero,
+    /// Used by boards that don't set their own policy.
+    pub policy: CaptchaPolicy,
+    /// How far back a previous post from the same address counts for `first_post`.
+    pub window: u64,
+    /// Leading zero bits a proof of work needs to replace the captcha, 0 turns it off. Every
+    /// bit doubles the work, the example configuration has a starting point for tuning it.
+    pub pow_difficulty: u32,
+    /// Asked instead of showing the image to those who can't see it, e.g. with a screen reader.
+    /// One is picked at random, without any there's no alternative to the image.
+    pub questions: Vec<CaptchaQuestion>,
+}
+
+impl Default for CaptchaConfig {
+    fn default() -> Self {
+        Self {
+            lifetime: NonZero(600),
+            pool_size: NonZero(10),
+            interval: NonZero(60),
+            policy: CaptchaPolicy::Always,
+            window: 86400,
+            pow_difficulty: 0,
+            questions: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct CaptchaQuestion {
+    pub question: String,
+    /// Compared without regard to case or surrounding whitespace.
+    pub answer: String,
+}
+
+/// When posters have to solve a captcha, signed in staff never do.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CaptchaPolicy {
+    Always,
+    /// Only when starting a new thread.
+    Threads,
+    /// Only when the address hasn't posted within the configured window.
+    FirstPost,
+    Never,
+}
+
+#[derive(Clone, Error, Debug, PartialEq, Eq)]
+#[error("unknown captcha policy `{0}`, expected always, threads, first_post or never")]
+pub struct CaptchaPolicyError(String);
+
+impl std::str::FromStr for CaptchaPolicy {
+    type Err = CaptchaPolicyError;
+
+    // same spelling as in the configuration file, see `boards.captcha_policy`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(CaptchaPolicy::Always),
+            "threads" => Ok(CaptchaPolicy::Threads),
+            "first_post" => Ok(CaptchaPolicy::FirstPost),
+            "never" => Ok(CaptchaPolicy::Never),
+            _ => Err(CaptchaPolicyError(s.to_owned())),
+        }
+    }
+}
+
 #[derive(Deserialize)]
 pub struct Security {
-    pub upload_limit: String,
+    pub upload_limit: ByteSize,
     pub allowed_mimes: Vec<String>,
+    /// Pairs of board names and titles that are created on startup if they don't exist yet.
+    #[serde(default)]
+    pub boards: Vec<(String, String)>,
+    /// Remove boards that only exist in the database instead of warning about them.
+    #[serde(default)]
+    pub prune_boards: bool,
+    /// Header a reverse proxy in front of the site appends the client's address to, like
+    /// `X-Forwarded-For`. Only set it when every request goes through that proxy, posters could
+    /// pick their own address otherwise.
+    #[serde(default)]
+    pub forwarded_header: Option<String>,
+}
+
+#[derive(Clone, Error, Debug, PartialEq, Eq)]
+pub enum ByteSizeError {
+    #[error("invalid size `{0}`, expected a number with an optional unit like 500, 10KB or 10MiB")]
+    Syntax(String),
+    #[error("size `{0}` is too large")]
+    Overflow(String),
+}
+
+/// An amount of bytes written as a number with an optional unit, `K`, `M` and `G` are SI
+/// prefixes (powers of 1000) while `Ki`, `Mi` and `Gi` are binary prefixes (powers of 1024).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct ByteSize(usize);
+
+impl ByteSize {
+    pub fn bytes(&self) -> usize {
+        self.0
+    }
+}
+
+impl TryFrom<String> for ByteSize {
+    type Error = ByteSizeError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl std::str::FromStr for ByteSize {
+    type Err = ByteSizeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let re =
+            Regex::new(r"^(?P<num>\d+)\s*(?:(?P<prefix>[KkMG])(?P<binary>i)?)?B?$").unwrap();
+        let captures = re
+            .captures(s.trim())
+            .ok_or_else(|| ByteSizeError::Syntax(s.to_owned()))?;
+
+        let num: usize = captures["num"]
+            .parse()
+            .map_err(|_| ByteSizeError::Overflow(s.to_owned()))?;
+        let exponent = match captures.name("prefix").map(|x| x.as_str()) {
+            None => 0,
+            Some("K" | "k") => 1,
+            Some("M") => 2,
+            Some(_) => 3,
+        };
+        let base: usize = match captures.name("binary") {
+            Some(_) => 1024,
+            None => 1000,
+        };
+
+        base.checked_pow(exponent)
+            .and_then(|x| x.checked_mul(num))
+            .map(ByteSize)
+            .ok_or_else(|| ByteSizeError::Overflow(s.to_owned()))
+    }
+}
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}B", self.0)
+    }
 }
 
-impl Security {
-    pub fn validate_upload_limit(&self) -> Result<usize, Report> {
-        let s = &self.upload_limit;
-        let re = Regex::new(r"^(?P<num>\d+)\s*(?:(?P<prefix>[KMG])(?P<base>i)?)?B?$")?;
+#[derive(Clone, Error, Debug, PartialEq, Eq)]
+#[error("expected a number greater than 0")]
+pub struct ZeroError;
 
-        match re.is_match(s) {
-            false => Err(regex::Error::Syntax(s.to_string()).into()),
-            true => {
-                event!(Level::INFO, "Regex is correct!");
+/// An interval, lifetime or amount that would stall a background task or leave it without work
+/// when it's 0, so such a configuration is refused on startup instead.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "u64")]
+pub struct NonZero(u64);
 
-                let n = ['K', 'M', 'G']
-                    .iter()
-                    .position(|&c| c == s.as_bytes()[s.len() - 1] as char)
-                    .unwrap();
-                Ok(Some(&s[0..s.len() - 2]).unwrap().parse::<usize>().unwrap()
-                    * 1024_usize.pow(n.try_into().unwrap()))
-            }
+impl NonZero {
+    pub fn get(&self) -> u64 {
+        self.0
+    }
+}
+
+impl TryFrom<u64> for NonZero {
+    type Error = ZeroError;
+
+    fn try_from(n: u64) -> Result<Self, Self::Error> {
+        match n {
+            0 => Err(ZeroError),
+            n => Ok(NonZero(n)),
         }
     }
 }
+
+#[test]
+fn test_byte_size_units() {
+    let parse = |s: &str| s.parse::<ByteSize>().map(|x| x.bytes());
+
+    assert_eq!(parse("500"), Ok(500));
+    assert_eq!(parse("500B"), Ok(500));
+    assert_eq!(parse("10KB"), Ok(10_000));
+    assert_eq!(parse("10kB"), Ok(10_000));
+    assert_eq!(parse("10KiB"), Ok(10_240));
+    assert_eq!(parse("10MB"), Ok(10_000_000));
+    assert_eq!(parse("10 MiB"), Ok(10 * 1024 * 1024));
+    assert_eq!(parse("1G"), Ok(1_000_000_000));
+    assert_eq!(parse("1GiB"), Ok(1024 * 1024 * 1024));
+}
+
+#[test]
+fn test_byte_size_errors() {
+    assert!(matches!("".parse::<ByteSize>(), Err(ByteSizeError::Syntax(_))));
+    assert!(matches!("10XB".parse::<ByteSize>(), Err(ByteSizeError::Syntax(_))));
+    assert!(matches!("MB".parse::<ByteSize>(), Err(ByteSizeError::Syntax(_))));
+    assert!(matches!("-1".parse::<ByteSize>(), Err(ByteSizeError::Syntax(_))));
+    assert!(matches!(
+        "99999999999999999999999GiB".parse::<ByteSize>(),
+        Err(ByteSizeError::Overflow(_))
+    ));
+}
+
+#[test]
+fn test_byte_size_deserialize() {
+    #[derive(Deserialize)]
+    struct Limits {
+        upload_limit: ByteSize,
+    }
+
+    let limits: Limits = toml::from_str(r#"upload_limit = "10MB""#).unwrap();
+    assert_eq!(limits.upload_limit.bytes(), 10_000_000);
+
+    let err = toml::from_str::<Limits>(r#"upload_limit = "ten""#).err().unwrap();
+    assert!(err.to_string().contains("invalid size `ten`"));
+}
+
+#[test]
+fn test_non_zero_deserialize() {
+    let config: CaptchaConfig = toml::from_str("interval = 30").unwrap();
+    assert_eq!(config.interval.get(), 30);
+    assert_eq!(config.lifetime.get(), 600);
+
+    let err = toml::from_str::<CaptchaConfig>("pool_size = 0").err().unwrap();
+    assert!(err.to_string().contains("expected a number greater than 0"));
+    assert!(err.to_string().contains("pool_size"));
+    assert!(toml::from_str::<ArchiveConfig>("interval = 0").is_err());
+}
+
+#[test]
+fn test_captcha_policy() {
+    let parse = |s: &str| s.parse::<CaptchaPolicy>();
+
+    assert_eq!(parse("always"), Ok(CaptchaPolicy::Always));
+    assert_eq!(parse("threads"), Ok(CaptchaPolicy::Threads));
+    assert_eq!(parse("first_post"), Ok(CaptchaPolicy::FirstPost));
+    assert_eq!(parse("never"), Ok(CaptchaPolicy::Never));
+    assert!(parse("sometimes").is_err());
+}
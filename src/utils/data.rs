@@ -3,32 +3,69 @@
 // i32 is used over u32 because this is a requirement by `sqlx` despite the types never being
 // negative
 
+use std::{str::FromStr, sync::Arc};
+
 use chrono::{DateTime, Utc};
 
+use password_hash::rand_core::OsRng;
+use password_hash::SaltString;
 use pbkdf2::{
-    password_hash::{PasswordHash, PasswordHasher},
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier},
     Pbkdf2,
 };
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use sqlx::types::Json;
+use tokio::sync::RwLock;
 
-use super::{config::Config, models::PoolModel};
+use super::{
+    config::{BoardConfig, CaptchaConfig, CaptchaPolicy, Config, Security},
+    error::LoginError,
+    helpers::{format_duration, format_size},
+    models::PoolModel,
+    storage::Storage,
+};
 
 
 pub struct App {
     pub models: PoolModel,
-    // support hot-reloading boards in the future
     pub config: Config,
-    pub boards: Vec<Board>,
+    pub boards: RwLock<Vec<Board>>,
+    pub storage: Arc<dyn Storage>,
 }
 
 impl App {
-    pub fn new(config: Config, models: PoolModel, boards: Vec<Board>) -> Self {
+    pub fn new(
+        config: Config,
+        models: PoolModel,
+        boards: Vec<Board>,
+        storage: Arc<dyn Storage>,
+    ) -> Self {
         Self {
             config,
             models,
-            boards,
+            boards: RwLock::new(boards),
+            storage,
         }
     }
+
+    pub async fn boards(&self) -> Vec<Board> {
+        self.boards.read().await.clone()
+    }
+
+    pub async fn board(&self, name: &str) -> Option<Board> {
+        self.boards
+            .read()
+            .await
+            .iter()
+            .find(|x| x.name == name)
+            .cloned()
+    }
+
+    /// Replaces the cached boards with the ones currently in the database.
+    pub async fn reload_boards(&self) {
+        let boards = self.models.get_boards().await;
+        *self.boards.write().await = boards;
+    }
 }
 
 pub struct ValidCaptcha(pub bool);
@@ -45,7 +82,90 @@ pub struct Post {
     pub subject: Option<String>,
     pub body: Option<String>,
 
-    pub files: Option<Vec<String>>,
+    /// Files in the order they were attached, `None` for posts without any.
+    pub files: Option<Json<Vec<Attachment>>>,
+
+    pub deleted_at: Option<DateTime<Utc>>,
+    pub deleted_by: Option<i32>,
+
+    pub sticky: bool,
+    pub locked: bool,
+    pub autosage: bool,
+
+    pub archived_at: Option<DateTime<Utc>>,
+}
+
+/// Metadata of an uploaded file, `name` is what `Attachment::name` refers to.
+#[derive(Clone, Debug)]
+pub struct FileInfo {
+    pub name: String,
+    pub hash: String,
+    pub mime: String,
+    pub size: i64,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub filename: Option<String>,
+    pub thumbnail: Option<String>,
+    pub phash: Option<i64>,
+    pub duration: Option<f32>,
+}
+
+#[derive(Clone, Debug)]
+pub struct BannedFile {
+    pub hash: String,
+    pub reason: Option<String>,
+    pub created: DateTime<Utc>,
+}
+
+/// A file as attached to a post, `filename` and `spoiler` can differ between posts of the
+/// same file.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Attachment {
+    pub name: String,
+    pub thumbnail: Option<String>,
+    pub filename: Option<String>,
+    pub size: Option<i64>,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub duration: Option<f32>,
+    pub spoiler: bool,
+}
+
+impl Attachment {
+    /// File info line such as `name.jpg (340 KB, 1280x720)` or `name.webm (2.1 MB, 640x360, 0:42)`.
+    pub fn info(&self) -> String {
+        let details: Vec<String> = self
+            .size
+            .map(format_size)
+            .into_iter()
+            .chain(self.width.zip(self.height).map(|(w, h)| format!("{}x{}", w, h)))
+            .chain(self.duration.map(format_duration))
+            .collect();
+        // the original name could give away what is being hidden
+        let name = match self.spoiler {
+            true => "Spoiler image",
+            false => self.filename.as_deref().unwrap_or(&self.name),
+        };
+
+        match details.is_empty() {
+            true => name.to_owned(),
+            false => format!("{} ({})", name, details.join(", ")),
+        }
+    }
+
+    pub fn is_video(&self) -> bool {
+        self.name.ends_with(".webm") || self.name.ends_with(".mp4")
+    }
+
+    pub fn is_audio(&self) -> bool {
+        self.name.ends_with(".mp3") || self.name.ends_with(".ogg")
+    }
+}
+
+impl Post {
+    pub fn attachments(&self) -> &[Attachment] {
+        self.files.as_deref().map(Vec::as_slice).unwrap_or_default()
+    }
 }
 
 impl Default for Post {
@@ -61,11 +181,20 @@ impl Default for Post {
             email: None,
             subject: None,
             body: None,
+
+            deleted_at: None,
+            deleted_by: None,
+
+            sticky: false,
+            locked: false,
+            autosage: false,
+
+            archived_at: None,
         }
     }
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Role {
     Admin,
     Moderator,
@@ -73,19 +202,58 @@ pub enum Role {
     User,
 }
 
+impl Role {
+    /// The name stored in `users.role`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::Moderator => "moderator",
+            Role::Volunteer => "volunteer",
+            Role::User => "user",
+        }
+    }
+
+    /// Whether the role belongs to the people running the site rather than a regular account.
+    pub fn is_staff(&self) -> bool {
+        matches!(self, Role::Admin | Role::Moderator | Role::Volunteer)
+    }
+}
+
+impl FromStr for Role {
+    type Err = LoginError;
+
+    // the inverse of `Role::as_str`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "admin" => Ok(Role::Admin),
+            "moderator" => Ok(Role::Moderator),
+            "volunteer" => Ok(Role::Volunteer),
+            "user" => Ok(Role::User),
+            _ => Err(LoginError::InvalidRole(s.to_owned())),
+        }
+    }
+}
+
 #[derive(Default, Debug, Deserialize)]
 pub struct Credentials {
     pub username: String,
     pub password: String,
-    pub salt: String,
     pub role: Option<Role>,
 }
 
 impl Credentials {
-    pub fn hash(&self) -> PasswordHash {
-        Pbkdf2
-            .hash_password(self.password.as_bytes(), &self.salt)
-            .unwrap()
+    /// A PHC string that embeds its own salt and parameters, as stored in `users.password`.
+    pub fn hash(&self) -> Result<String, password_hash::Error> {
+        let salt = SaltString::generate(&mut OsRng);
+        Ok(Pbkdf2
+            .hash_password(self.password.as_bytes(), &salt)?
+            .to_string())
+    }
+
+    pub fn verify(&self, hash: &str) -> bool {
+        PasswordHash::new(hash)
+            .and_then(|hash| Pbkdf2.verify_password(self.password.as_bytes(), &hash))
+            .is_ok()
     }
 
     pub fn authenticate(&self) -> bool {
@@ -95,10 +263,139 @@ impl Credentials {
     }
 }
 
+#[derive(Debug, Deserialize)]
+pub struct DeleteThread {
+    pub delete_thread: i32,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BanFile {
+    pub ban_post: i32,
+    #[serde(default)]
+    pub reason: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UnbanFile {
+    pub unban_hash: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RestoreThread {
+    pub restore_thread: i32,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EditThread {
+    pub edit_thread: i32,
+    #[serde(default)]
+    pub sticky: bool,
+    #[serde(default)]
+    pub locked: bool,
+    #[serde(default)]
+    pub autosage: bool,
+}
+
+// amount of file inputs in the post form
+pub const MAX_FILES: usize = 3;
+
+pub struct UploadPolicy<'a> {
+    pub max_file_size: usize,
+    pub max_files: usize,
+    pub allowed_mimes: &'a [String],
+    pub text_only: bool,
+    /// Store images whose metadata can't be removed as they are instead of rejecting them.
+    pub keep_originals: bool,
+}
+
+/// Upper bound for a single text field of the post form.
+pub const TEXT_FIELD_LIMIT: usize = 64 * 1024;
+
+impl UploadPolicy<'_> {
+    /// Upper bound for a whole multipart body, leaves room for the text fields.
+    pub fn body_limit(&self) -> usize {
+        match self.text_only {
+            true => TEXT_FIELD_LIMIT,
+            false => self
+                .max_file_size
+                .saturating_mul(self.max_files)
+                .saturating_add(TEXT_FIELD_LIMIT),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BoardForm {
+    pub name: String,
+    #[serde(default)]
+    pub title: String,
+}
+
 #[derive(Clone)]
 pub struct Board {
     pub name: String,
     pub title: String,
+    pub threads_per_page: Option<i32>,
+    pub max_pages: Option<i32>,
+
+    pub max_file_size: Option<i64>,
+    pub allowed_mimes: Option<Vec<String>>,
+    pub max_files: Option<i32>,
+    pub default_name: Option<String>,
+    pub text_only: bool,
+    pub nsfw: bool,
+    pub keep_originals: bool,
+    pub captcha_policy: Option<String>,
+    pub pow_difficulty: Option<i32>,
+}
+
+impl Board {
+    pub fn threads_per_page(&self, config: &BoardConfig) -> i64 {
+        self.threads_per_page
+            .map(i64::from)
+            .unwrap_or(config.threads_per_page)
+    }
+
+    pub fn max_pages(&self, config: &BoardConfig) -> i64 {
+        self.max_pages.map(i64::from).unwrap_or(config.max_pages)
+    }
+
+    /// Board settings take precedence over the global ones in `Security`.
+    pub fn upload_policy<'a>(&'a self, security: &'a Security) -> UploadPolicy<'a> {
+        UploadPolicy {
+            max_file_size: self
+                .max_file_size
+                .map(|n| n as usize)
+                .unwrap_or(security.upload_limit.bytes()),
+            max_files: self.max_files.map(|n| n as usize).unwrap_or(MAX_FILES),
+            allowed_mimes: self.allowed_mimes.as_deref().unwrap_or(&security.allowed_mimes),
+            text_only: self.text_only,
+            keep_originals: self.keep_originals,
+        }
+    }
+
+    /// The board's policy takes precedence over the one in `CaptchaConfig`.
+    pub fn captcha_policy(&self, config: &CaptchaConfig) -> CaptchaPolicy {
+        self.captcha_policy
+            .as_deref()
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(config.policy)
+    }
+
+    pub fn pow_difficulty(&self, config: &CaptchaConfig) -> u32 {
+        self.pow_difficulty
+            .map(|n| n as u32)
+            .unwrap_or(config.pow_difficulty)
+    }
+
+    pub fn default_name(&self) -> &str {
+        self.default_name.as_deref().unwrap_or("Anonymous")
+    }
+
+    /// Amount of threads a board holds before the oldest ones get archived.
+    pub fn capacity(&self, config: &BoardConfig) -> i64 {
+        self.threads_per_page(config) * self.max_pages(config)
+    }
 }
 
 pub struct User {
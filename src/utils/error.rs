@@ -20,6 +20,20 @@ pub enum LoginError {
     EmptyUsername,
     #[error("no password provided")]
     EmptyPassword,
+    #[error("unknown role {0}")]
+    InvalidRole(String),
+}
+
+#[derive(Clone, Error, Debug)]
+pub enum BoardError {
+    #[error("board names must be a single character")]
+    InvalidName,
+    #[error("board titles must be between 1 and 63 characters")]
+    InvalidTitle,
+    #[error("board {0} already exists")]
+    Exists(String),
+    #[error("board {0} does not exist")]
+    NonExistent(String),
 }
 
 #[derive(Clone, Error, Debug)]
@@ -32,6 +46,41 @@ pub enum RequestError {
     TimeoutLimit,
     #[error("no key for one or more fields")]
     MissingKey,
+    #[error("invalid thread id {0}")]
+    InvalidParent(String),
+    #[error("thread {0} does not exist")]
+    NonExistentThread(i32),
+    #[error("thread is locked")]
+    LockedThread,
+    #[error("thread is archived")]
+    ArchivedThread,
+    #[error("this board doesn't allow files")]
+    TextOnly,
+    #[error("too many files")]
+    TooManyFiles,
+    #[error("files of type {0} are not allowed")]
+    UnsupportedType(String),
+    #[error("metadata can't be removed from {0} files")]
+    Metadata(String),
+    #[error("this file has been banned")]
+    BannedFile,
+    #[error("unexpected field {0}")]
+    UnknownField(String),
+    #[error("malformed request: {0}")]
+    Malformed(String),
+}
+
+impl From<Report> for RequestError {
+    fn from(e: Report) -> Self {
+        match e.downcast::<RequestError>() {
+            Ok(e) => e,
+            Err(e) => match e.downcast_ref::<multer::Error>() {
+                Some(multer::Error::StreamSizeExceeded { .. })
+                | Some(multer::Error::FieldSizeExceeded { .. }) => RequestError::SizeLimit,
+                _ => RequestError::Malformed(e.to_string()),
+            },
+        }
+    }
 }
 
 impl IntoResponse for LoginError {
@@ -1,8 +1,9 @@
-use std::{fs, io::ErrorKind};
+use std::sync::Arc;
 
 use super::{
     data::Post,
-    helpers::{format_name, save_file},
+    helpers::format_name,
+    storage::Storage,
 };
 use chrono::Utc;
 use color_eyre::Report;
@@ -18,54 +19,49 @@ use sqlx::postgres::PgPool;
 
 use tracing::info;
 
+/// Mocker images share the storage with real uploads, they're told apart by this prefix so
+/// neither uploads nor their thumbnails get picked up as mock images.
+const MOCK_PREFIX: &str = "mock_";
+
 pub struct ImagePool<'a> {
     pool: &'a PgPool,
+    storage: Arc<dyn Storage>,
     images: Vec<String>,
 }
 
 impl<'a> ImagePool<'a> {
-    pub async fn new(pool: &'a PgPool, size: usize) -> Result<ImagePool, Report> {
-        let fetch = |s: String| async move {
-            let http_client = reqwest::Client::new();
-
-            let resp = http_client.get(s.clone()).send().await?;
-            let bytes = resp.bytes().await?;
-
-            let name: Vec<_> = s.split('/').collect();
-            let name = name.last().unwrap();
-            let name: Vec<_> = name.split('.').collect();
-
-            let name = format_name((name[0].as_bytes(), name[1].to_owned()));
-            save_file(name.clone(), &bytes).await?;
-
-            Ok::<String, Report>(name.to_owned())
-        };
-
-        let r = match fs::read_dir("./.tmp") {
-            Ok(mut r) => {
-                if r.next().is_some() {
-                    Some(r)
-                } else {
-                    None
-                }
-            }
-            Err(e) if e.kind() == ErrorKind::NotFound => {
-                fs::create_dir("./.tmp")?;
-                None
+    pub async fn new(
+        pool: &'a PgPool,
+        storage: Arc<dyn Storage>,
+        size: usize,
+    ) -> Result<ImagePool, Report> {
+        let fetch = |s: String| {
+            let storage = storage.clone();
+            async move {
+                let http_client = reqwest::Client::new();
+
+                let resp = http_client.get(s.clone()).send().await?;
+                let bytes = resp.bytes().await?;
+
+                let name: Vec<_> = s.split('/').collect();
+                let name = name.last().unwrap();
+                let name: Vec<_> = name.split('.').collect();
+
+                let name = MOCK_PREFIX.to_owned() + &format_name((&bytes[..], name[1].to_owned()));
+                storage.put(&name, &bytes).await?;
+
+                Ok::<String, Report>(name.to_owned())
             }
-            Err(e) => return Err(e.into()),
         };
-        match r {
-            Some(r) => {
-                info!("mocker images already present");
-                let images = r
-                    .into_iter()
-                    .map(|s| s.unwrap().file_name().to_str().unwrap().to_owned())
-                    .collect();
 
-                Ok(Self { pool, images })
-            }
-            None => {
+        let images: Vec<_> = storage
+            .list()
+            .await?
+            .into_iter()
+            .filter(|x| x.starts_with(MOCK_PREFIX))
+            .collect();
+        match images.len() {
+            0 => {
                 info!("fetching mocker images");
                 let images = Subreddit::new("onetruebiribiri")
                     .top(
@@ -75,7 +71,7 @@ impl<'a> ImagePool<'a> {
                     .await?;
 
                 // filter out videos, text-only threads
-                let files: _ = images
+                let files = images
                     .data
                     .children
                     .iter()
@@ -85,7 +81,19 @@ impl<'a> ImagePool<'a> {
                     .map(fetch);
 
                 let images = try_join_all(files).await?;
-                Ok(Self { pool, images })
+                Ok(Self {
+                    pool,
+                    storage,
+                    images,
+                })
+            }
+            _ => {
+                info!("mocker images already present");
+                Ok(Self {
+                    pool,
+                    storage,
+                    images,
+                })
             }
         }
     }
@@ -93,24 +101,29 @@ impl<'a> ImagePool<'a> {
     pub async fn truncate(&mut self) -> Result<(), Report> {
         let old_files = sqlx::query!(
             "
-            SELECT files FROM posts
+            SELECT name, thumbnail FROM files
         ",
         )
         .fetch_all(self.pool)
         .await?;
 
-        old_files
-            .iter()
-            .flat_map(|x| x.files.to_owned())
-            .flatten()
-            .try_for_each(fs::remove_file)
-            .map_err(|e| info!("failed to delete image: {}", e))
-            .ok();
         info!("deleting old mocker images");
+        let old_files = old_files
+            .into_iter()
+            .flat_map(|x| [Some(x.name), x.thumbnail])
+            .flatten()
+            .filter(|x| !self.images.contains(x));
+
+        for name in old_files {
+            if let Err(e) = self.storage.delete(&name).await {
+                info!("failed to delete image: {}", e);
+            }
+        }
 
+        // attachments go along with the posts, their rows would point at reused ids otherwise
         sqlx::query!(
             "
-            TRUNCATE posts
+            TRUNCATE posts, post_files, files
         ",
         )
         .execute(self.pool)
@@ -132,15 +145,15 @@ impl<'a> ImagePool<'a> {
 
         for _ in 1..=ops {
             let files = self.random_files(1).await;
-            let post = self.create(files, None).await;
-            self.insert(post).await?;
+            let post = self.create(None).await;
+            self.insert(post, &files).await?;
         }
         for i in 1..=ops {
             let res = (0..children)
                 .map(|_| async {
                     let files = self.random_files(1).await;
-                    let child = self.create(files, Some(i as i32)).await;
-                    self.insert(child).await
+                    let child = self.create(Some(i as i32)).await;
+                    self.insert(child, &files).await
                 })
                 .collect::<Vec<_>>();
 
@@ -150,7 +163,7 @@ impl<'a> ImagePool<'a> {
         Ok(())
     }
 
-    async fn create(&self, files: Vec<String>, parent: Option<i32>) -> Post {
+    async fn create(&self, parent: Option<i32>) -> Post {
         Post {
             id: 0,
             parent,
@@ -161,21 +174,25 @@ impl<'a> ImagePool<'a> {
             email: Some(FreeEmail(EN).fake()),
             body: Some(Sentence(EN, 1..5).fake()),
             subject: Some(Words(EN, 1..5).fake::<Vec<String>>().join(" ")),
-            files: Some(files),
+            files: None,
+
+            deleted_at: None,
+            deleted_by: None,
+
+            sticky: false,
+            locked: false,
+            autosage: false,
+
+            archived_at: None,
         }
     }
 
-    async fn insert(&self, post: Post) -> Result<(), Report> {
-        let files = if let Some(files) = post.files {
-            files
-        } else {
-            Vec::new()
-        };
-
-        sqlx::query!(
+    async fn insert(&self, post: Post, files: &[String]) -> Result<(), Report> {
+        let id = sqlx::query_scalar!(
             "
-           INSERT INTO posts (board, parent, op, email, body, subject, files)
-           VALUES ($1, $2, $3, $4, $5, $6, $7)
+           INSERT INTO posts (board, parent, op, email, body, subject)
+           VALUES ($1, $2, $3, $4, $5, $6)
+           RETURNING id
         ",
             post.board,
             post.parent,
@@ -183,11 +200,49 @@ impl<'a> ImagePool<'a> {
             post.email,
             post.subject,
             post.body,
-            &files,
         )
-        .execute(self.pool)
+        .fetch_one(self.pool)
         .await?;
 
+        for (position, name) in files.iter().enumerate() {
+            // mocker names are `mock_<hash>.<extension>`, which is all there is to know
+            let (hash, extension) = name
+                .trim_start_matches(MOCK_PREFIX)
+                .split_once('.')
+                .unwrap_or((name, "jpeg"));
+            let mime = match extension {
+                "jpg" => "image/jpeg".to_owned(),
+                extension => format!("image/{}", extension),
+            };
+            let size = self.storage.size(name).await?.unwrap_or_default();
+
+            // the mocker images are small enough to be their own thumbnails
+            sqlx::query!(
+                "
+               INSERT INTO files (name, hash, mime, size, thumbnail)
+               VALUES ($1, $2, $3, $4, $1)
+               ON CONFLICT (hash) DO NOTHING
+            ",
+                name,
+                hash,
+                mime,
+                size as i64,
+            )
+            .execute(self.pool)
+            .await?;
+
+            sqlx::query!(
+                "
+               INSERT INTO post_files (post, file, position) VALUES ($1, $2, $3)
+            ",
+                id,
+                hash,
+                position as i16,
+            )
+            .execute(self.pool)
+            .await?;
+        }
+
         Ok(())
     }
 
@@ -205,18 +260,23 @@ impl<'a> ImagePool<'a> {
 
 #[sqlx::test]
 async fn test_new(pool: PgPool) -> Result<(), Report> {
-    let object = ImagePool::new(&pool, 3).await?;
-    let dir = fs::read_dir("./.tmp/")?;
+    let root = crate::utils::helpers::test_dir("mocker");
+    let storage = Arc::new(crate::utils::storage::LocalStorage::new(&root)?);
+    let object = ImagePool::new(&pool, storage, 3).await?;
+    let dir = std::fs::read_dir(&root)?;
     let len = dir.into_iter().count();
 
     assert_eq!(len, object.images.len());
+    assert!(object.images.iter().all(|x| x.starts_with(MOCK_PREFIX)));
 
     Ok(())
 }
 
 #[sqlx::test]
 async fn test_truncate(pool: PgPool) -> Result<(), Report> {
-    let mut object = ImagePool::new(&pool, 3).await?;
+    let root = crate::utils::helpers::test_dir("mocker");
+    let storage = Arc::new(crate::utils::storage::LocalStorage::new(&root)?);
+    let mut object = ImagePool::new(&pool, storage, 3).await?;
     object.truncate().await?;
 
     let v = sqlx::query!(
@@ -229,12 +289,19 @@ async fn test_truncate(pool: PgPool) -> Result<(), Report> {
 
     assert_eq!(v.len(), 0);
 
+    let links = sqlx::query_scalar!(r#"SELECT count(*) AS "count!" FROM post_files"#)
+        .fetch_one(&pool)
+        .await?;
+    assert_eq!(links, 0);
+
     Ok(())
 }
 
 #[sqlx::test]
 async fn test_mock(pool: PgPool) -> Result<(), Report> {
-    let mut object = ImagePool::new(&pool, 3).await?;
+    let root = crate::utils::helpers::test_dir("mocker");
+    let storage = Arc::new(crate::utils::storage::LocalStorage::new(&root)?);
+    let mut object = ImagePool::new(&pool, storage, 3).await?;
     object.mock(5, 3).await?;
 
     let parents = sqlx::query!(
@@ -1,31 +1,46 @@
 use axum::headers::Header;
 
-use axum::http::Method;
+use axum::body::{Bytes, StreamBody};
+use axum::http::header::{
+    ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, RANGE, REFERER,
+};
+use axum::http::{HeaderMap, Method};
 use axum::http::StatusCode;
 use axum::response::Response;
 
 use axum::BoxError;
 use axum::Extension;
+use axum::Json;
+use axum_sessions::async_session::Session;
 use axum_sessions::extractors::ReadableSession;
 
 use color_eyre::Result;
+use futures::TryStreamExt;
 use digest::Digest;
 use hmac::Mac;
 use http_body::Full;
+use tokio::sync::RwLock;
 use tokio::time::sleep;
 
+use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
 
 use tower::timeout::error::Elapsed;
 
-use super::data::Credentials;
+use super::data::{
+    BanFile, BoardForm, Credentials, DeleteThread, EditThread, RestoreThread, Role, UnbanFile,
+};
 
+use super::captcha::{CaptchaService, PowChallenge, TextCaptcha};
 use super::error::RequestError;
+use super::helpers::{client_ip, parse_range, sniff_mime, ByteRange, SNIFF_LENGTH};
+use super::models::StagedFile;
 use super::templates::*;
 use crate::App;
 use axum::debug_handler;
 
+use axum::extract::ConnectInfo;
 use axum::extract::Path;
 use axum::extract::State;
 use axum::http::Uri;
@@ -39,7 +54,7 @@ pub async fn get_root(State(app): State<Arc<App>>, session: ReadableSession) ->
         base: BaseTemplate {
             authenticated: session.get("authenticated").unwrap_or(false),
             current_year: 2022u32,
-            boards: app.boards.clone(),
+            boards: app.boards().await,
             captcha: Some("foobar".to_owned()),
             flash: None,
         },
@@ -47,25 +62,37 @@ pub async fn get_root(State(app): State<Arc<App>>, session: ReadableSession) ->
 }
 
 pub async fn get_recent(State(app): State<Arc<App>>, session: ReadableSession) -> Response {
-    let posts = app.models.recent().await;
+    let staff = is_staff(&session);
+    let posts = app
+        .models
+        .recent(app.config.board.threads_per_page, staff)
+        .await;
 
     HtmlTemplate(BoardTemplate {
         base: BaseTemplate {
             authenticated: session.get("authenticated").unwrap_or(false),
             current_year: 2022u32,
-            boards: app.boards.clone(),
+            boards: app.boards().await,
             captcha: Some("foobar".to_owned()),
             flash: None,
         },
         board: "recent".to_owned(),
+        current: None,
         posts,
         input: Input::default(),
+        page: 1,
+        pages: vec![],
     })
     .into_response()
 }
 
 pub async fn get_mod(State(app): State<Arc<App>>, session: ReadableSession) -> Response {
     if let Some(_) = session.get::<bool>("authenticated") {
+        let banned = match app.models.banned_files().await {
+            Ok(banned) => banned,
+            Err(e) => return e.to_string().into_response(),
+        };
+
         HtmlTemplate(ModTemplate {
             credentials: Credentials {
                 username: "".to_owned(),
@@ -74,10 +101,11 @@ pub async fn get_mod(State(app): State<Arc<App>>, session: ReadableSession) -> R
             base: BaseTemplate {
                 authenticated: true,
                 current_year: 2022u32,
-                boards: app.boards.clone(),
+                boards: app.boards().await,
                 captcha: Some("foobar".to_owned()),
                 flash: None,
             },
+            banned,
         })
         .into_response()
     } else {
@@ -97,7 +125,7 @@ pub async fn get_login(State(app): State<Arc<App>>, session: ReadableSession) ->
             base: BaseTemplate {
                 authenticated: false,
                 current_year: 2022u32,
-                boards: app.boards.clone(),
+                boards: app.boards().await,
                 captcha: Some("foobar".to_owned()),
                 flash: None,
             },
@@ -106,10 +134,17 @@ pub async fn get_login(State(app): State<Arc<App>>, session: ReadableSession) ->
     }
 }
 
-pub async fn get_signup(
-    State(app): State<Arc<App>>,
-    _session: ReadableSession,
-) -> impl IntoResponse {
+/// Accounts are handed out by admins, only the very first one can be created by anyone.
+async fn can_sign_up(app: &App, session: &ReadableSession) -> bool {
+    session.get::<Role>("role") == Some(Role::Admin)
+        || !app.models.has_users().await.unwrap_or(true)
+}
+
+pub async fn get_signup(State(app): State<Arc<App>>, session: ReadableSession) -> Response {
+    if !can_sign_up(&app, &session).await {
+        return Redirect::to("/").into_response();
+    }
+
     HtmlTemplate(SignupTemplate {
         credentials: Credentials {
             username: "".to_owned(),
@@ -118,11 +153,12 @@ pub async fn get_signup(
         base: BaseTemplate {
             authenticated: true,
             current_year: 2022u32,
-            boards: app.boards.clone(),
+            boards: app.boards().await,
             captcha: Some("foobar".to_owned()),
             flash: None,
         },
     })
+    .into_response()
 }
 
 pub async fn not_found(Path(board): Path<String>) -> impl IntoResponse {
@@ -131,7 +167,7 @@ pub async fn not_found(Path(board): Path<String>) -> impl IntoResponse {
     //     posts,
     //     current_year: 2022i32,
     //     board: "b".to_owned(),
-    //     boards: app.boards.clone(),
+    //     boards: app.boards().await,
     //     captcha: "foobar".to_owned(),
     //     flash: false,
     //     authenticated: session,
@@ -145,23 +181,102 @@ pub async fn get_board(
     Path(board): Path<String>,
     session: ReadableSession,
 ) -> impl IntoResponse {
-    if !app.boards.iter().any(|x| x.name == board) {
-        dbg!("not found!");
+    board_page(app, board, 1, session).await
+}
+
+pub async fn get_board_page(
+    State(app): State<Arc<App>>,
+    Path((board, page)): Path<(String, i64)>,
+    session: ReadableSession,
+) -> impl IntoResponse {
+    board_page(app, board, page, session).await
+}
+
+async fn board_page(app: Arc<App>, board: String, page: i64, session: ReadableSession) -> Response {
+    let current = match app.board(&board).await {
+        Some(current) => current,
+        None => return not_found(Path(board)).await.into_response(),
+    };
+
+    let per_page = current.threads_per_page(&app.config.board);
+    let max_pages = current.max_pages(&app.config.board);
+    if page < 1 || page > max_pages {
         return not_found(Path(board)).await.into_response();
     }
-    let posts = app.models.get_board(board.clone()).await;
+
+    let staff = is_staff(&session);
+    let threads = app.models.count_threads(&board, staff).await;
+    let pages = ((threads + per_page - 1) / per_page).clamp(1, max_pages);
+
+    let posts = app
+        .models
+        .get_board(board.clone(), page - 1, per_page, staff)
+        .await;
 
     HtmlTemplate(BoardTemplate {
         base: BaseTemplate {
             authenticated: session.get("authenticated").unwrap_or(false),
             current_year: 2022u32,
-            boards: app.boards.clone(),
+            boards: app.boards().await,
             captcha: Some("foobar".to_owned()),
             flash: None,
         },
         board,
+        current: Some(current),
         posts,
         input: Input::default(),
+        page,
+        pages: (1..=pages).collect(),
+    })
+    .into_response()
+}
+
+pub async fn get_archive(
+    State(app): State<Arc<App>>,
+    Path(board): Path<String>,
+    session: ReadableSession,
+) -> Response {
+    archive_page(app, board, 1, session).await
+}
+
+pub async fn get_archive_page(
+    State(app): State<Arc<App>>,
+    Path((board, page)): Path<(String, i64)>,
+    session: ReadableSession,
+) -> Response {
+    archive_page(app, board, page, session).await
+}
+
+async fn archive_page(app: Arc<App>, board: String, page: i64, session: ReadableSession) -> Response {
+    if app.board(&board).await.is_none() {
+        return not_found(Path(board)).await.into_response();
+    }
+
+    let staff = is_staff(&session);
+    let per_page = app.config.archive.per_page.get() as i64;
+    let threads = app.models.count_archived(&board, staff).await;
+    let pages = ((threads + per_page - 1) / per_page).max(1);
+    if page < 1 || page > pages {
+        return not_found(Path(board)).await.into_response();
+    }
+
+    let posts = app
+        .models
+        .get_archive(board.clone(), page - 1, per_page, staff)
+        .await;
+
+    HtmlTemplate(ArchiveTemplate {
+        base: BaseTemplate {
+            authenticated: session.get("authenticated").unwrap_or(false),
+            current_year: 2022u32,
+            boards: app.boards().await,
+            captcha: Some("foobar".to_owned()),
+            flash: None,
+        },
+        board,
+        posts,
+        page,
+        pages: (1..=pages).collect(),
     })
     .into_response()
 }
@@ -169,7 +284,7 @@ pub async fn get_board(
 pub async fn get_post(
     State(app): State<Arc<App>>,
     Path((board, id)): Path<(String, String)>,
-    session: WritableSession,
+    mut session: WritableSession,
 ) -> impl IntoResponse {
     // sleep(Duration::from_secs(8)).await;
     if id.parse::<u32>().is_err() || id.parse::<i32>().is_err() {
@@ -177,40 +292,62 @@ pub async fn get_post(
     }
 
     let id = id.parse::<i32>().unwrap();
+    let staff = is_staff(&session);
 
-    let post = app.models.get_post(id).await;
+    let post = match app.models.get_post(id, staff).await {
+        Some(post) => post,
+        None => return not_found(Path(id.to_string())).await.into_response(),
+    };
     // let captcha = generate();
 
     if let Some(p) = post.parent {
         return not_found(Path(p.to_string())).await.into_response();
     }
 
-    let children = app.models.children(id).await;
+    let current = match app.board(&post.board).await {
+        Some(current) => current,
+        None => return not_found(Path(board)).await.into_response(),
+    };
+
+    let children = app.models.children(id, staff).await;
+    let flash = session.get::<String>("flash");
+    session.remove("flash");
+
+    let input = Input {
+        op: current.default_name().to_owned(),
+        ..Default::default()
+    };
 
     HtmlTemplate(ThreadTemplate {
         invalid_captcha: false,
+        captcha_questions: !app.config.captcha.questions.is_empty(),
+        archived: post.archived_at.is_some(),
         base: BaseTemplate {
             authenticated: true,
             // authenticated: session,
             current_year: 2022u32,
-            boards: app.boards.clone(),
+            boards: app.boards().await,
             captcha: Some("".to_owned()),
-            flash: session.get("flash"),
+            flash,
         },
         board,
+        current,
         post,
         children,
-        input: Input::default(),
+        input,
     })
     .into_response()
 }
 
 pub async fn signup(
     State(app): State<Arc<App>>,
-    // Extension(session): Extension<Session>,
+    session: ReadableSession,
     Form(credentials): Form<Credentials>,
 ) -> Redirect {
-    dbg!(&credentials);
+    if !can_sign_up(&app, &session).await {
+        return Redirect::to("/");
+    }
+
     let _result = app.models.signup(credentials).await;
     Redirect::to("/.toki/mod")
 }
@@ -222,8 +359,10 @@ pub async fn login(
     Form(credentials): Form<Credentials>,
 ) -> Response {
     match app.models.login(credentials).await {
-        Ok(_x) => {
+        Ok((id, role)) => {
             session.insert("signed_in", true).unwrap();
+            session.insert("user_id", id).unwrap();
+            session.insert("role", role).unwrap();
             Redirect::to("/.toki/mod").into_response()
         }
         Err(e) => HtmlTemplate(LoginTemplate {
@@ -234,7 +373,7 @@ pub async fn login(
             base: BaseTemplate {
                 authenticated: false,
                 current_year: 2022u32,
-                boards: app.boards.clone(),
+                boards: app.boards().await,
                 captcha: Some("foobar".to_owned()),
                 flash: Some(e.to_string()),
             },
@@ -251,6 +390,141 @@ pub async fn logout(
     Redirect::to("/")
 }
 
+pub async fn delete_thread(
+    State(app): State<Arc<App>>,
+    session: ReadableSession,
+    Form(form): Form<DeleteThread>,
+) -> Response {
+    let user = match session.get::<i32>("user_id") {
+        Some(user) if is_staff(&session) => user,
+        _ => return Redirect::to("/").into_response(),
+    };
+
+    match app.models.delete_thread(form.delete_thread, user).await {
+        Ok(_) => Redirect::to("/.toki/mod").into_response(),
+        Err(e) => e.to_string().into_response(),
+    }
+}
+
+pub async fn ban_file(
+    State(app): State<Arc<App>>,
+    session: ReadableSession,
+    Form(form): Form<BanFile>,
+) -> Response {
+    let user = match session.get::<i32>("user_id") {
+        Some(user) if is_staff(&session) => user,
+        _ => return Redirect::to("/").into_response(),
+    };
+
+    match app
+        .models
+        .ban_post_files(form.ban_post, &form.reason, user)
+        .await
+    {
+        Ok(_) => Redirect::to("/.toki/mod").into_response(),
+        Err(e) => e.to_string().into_response(),
+    }
+}
+
+pub async fn unban_file(
+    State(app): State<Arc<App>>,
+    session: ReadableSession,
+    Form(form): Form<UnbanFile>,
+) -> Response {
+    if !is_staff(&session) {
+        return Redirect::to("/").into_response();
+    }
+
+    match app.models.unban_file(&form.unban_hash).await {
+        Ok(_) => Redirect::to("/.toki/mod").into_response(),
+        Err(e) => e.to_string().into_response(),
+    }
+}
+
+pub async fn restore_thread(
+    State(app): State<Arc<App>>,
+    session: ReadableSession,
+    Form(form): Form<RestoreThread>,
+) -> Response {
+    if !is_staff(&session) {
+        return Redirect::to("/").into_response();
+    }
+
+    match app.models.restore_thread(form.restore_thread).await {
+        Ok(_) => Redirect::to("/.toki/mod").into_response(),
+        Err(e) => e.to_string().into_response(),
+    }
+}
+
+pub async fn edit_thread(
+    State(app): State<Arc<App>>,
+    session: ReadableSession,
+    Form(form): Form<EditThread>,
+) -> Response {
+    if !is_staff(&session) {
+        return Redirect::to("/").into_response();
+    }
+
+    match app.models.edit_thread(&form).await {
+        Ok(_) => Redirect::to("/.toki/mod").into_response(),
+        Err(e) => e.to_string().into_response(),
+    }
+}
+
+pub async fn create_board(
+    State(app): State<Arc<App>>,
+    session: ReadableSession,
+    Form(form): Form<BoardForm>,
+) -> Response {
+    if session.get::<Role>("role") != Some(Role::Admin) {
+        return Redirect::to("/").into_response();
+    }
+
+    match app.models.create_board(&form).await {
+        Ok(_) => {
+            app.reload_boards().await;
+            Redirect::to("/.toki/mod").into_response()
+        }
+        Err(e) => e.to_string().into_response(),
+    }
+}
+
+pub async fn rename_board(
+    State(app): State<Arc<App>>,
+    session: ReadableSession,
+    Form(form): Form<BoardForm>,
+) -> Response {
+    if session.get::<Role>("role") != Some(Role::Admin) {
+        return Redirect::to("/").into_response();
+    }
+
+    match app.models.rename_board(&form).await {
+        Ok(_) => {
+            app.reload_boards().await;
+            Redirect::to("/.toki/mod").into_response()
+        }
+        Err(e) => e.to_string().into_response(),
+    }
+}
+
+pub async fn delete_board(
+    State(app): State<Arc<App>>,
+    session: ReadableSession,
+    Form(form): Form<BoardForm>,
+) -> Response {
+    if session.get::<Role>("role") != Some(Role::Admin) {
+        return Redirect::to("/").into_response();
+    }
+
+    match app.models.delete_board(&form.name).await {
+        Ok(_) => {
+            app.reload_boards().await;
+            Redirect::to("/.toki/mod").into_response()
+        }
+        Err(e) => e.to_string().into_response(),
+    }
+}
+
 pub async fn captcha(Extension(bytes): Extension<Vec<u8>>) -> Response {
     Response::builder()
         .header("Content-Type", "image/png")
@@ -259,22 +533,188 @@ pub async fn captcha(Extension(bytes): Extension<Vec<u8>>) -> Response {
         .into_response()
 }
 
+pub async fn captcha_question(Extension(captcha): Extension<TextCaptcha>) -> Response {
+    Response::builder()
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .header("Cache-Control", "no-store")
+        .body(Full::from(captcha.question))
+        .unwrap()
+        .into_response()
+}
+
+pub async fn pow_challenge(State(app): State<Arc<App>>, Path(board): Path<String>) -> Response {
+    let difficulty = match app.board(&board).await {
+        Some(board) => board.pow_difficulty(&app.config.captcha),
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+    // the board only accepts captchas
+    if difficulty == 0 {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    let nonce = hex::encode(rand::random::<[u8; 20]>());
+    let lifetime = app.config.captcha.lifetime.get() as f64;
+    match app.models.create_pow(&nonce, lifetime).await {
+        Ok(()) => Json(PowChallenge { nonce, difficulty }).into_response(),
+        Err(e) => {
+            tracing::error!("failed to store proof of work challenge: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+pub async fn captcha_stats(
+    Extension(cs): Extension<Arc<RwLock<CaptchaService>>>,
+    session: ReadableSession,
+) -> Response {
+    if !is_staff(&session) {
+        return Redirect::to("/").into_response();
+    }
+
+    Json(cs.read().await.stats().await).into_response()
+}
+
+pub async fn get_file(
+    State(app): State<Arc<App>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    match serve_file(&app, &name, &headers).await {
+        Ok(Some(response)) => response,
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            tracing::error!("failed to read {}: {}", name, e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Streams a stored file, or the part of it a `Range` header asks for so players can seek
+/// without downloading everything before it.
+async fn serve_file(app: &App, name: &str, headers: &HeaderMap) -> Result<Option<Response>> {
+    let size = match app.storage.size(name).await? {
+        Some(size) => size,
+        None => return Ok(None),
+    };
+
+    // the same detection uploads went through, so media isn't served as a download
+    let head = match app.storage.stream(name, 0..size.min(SNIFF_LENGTH as u64)).await? {
+        Some(stream) => {
+            let read = |mut head: Vec<u8>, chunk: Bytes| async move {
+                head.extend_from_slice(&chunk);
+                Ok(head)
+            };
+            stream.try_fold(Vec::new(), read).await?
+        }
+        None => return Ok(None),
+    };
+    let mime = sniff_mime(&head)
+        .unwrap_or("application/octet-stream")
+        .to_owned();
+
+    let range = headers.get(RANGE).and_then(|x| x.to_str().ok());
+    let (status, range) = match parse_range(range, size) {
+        ByteRange::Full => (StatusCode::OK, 0..size),
+        ByteRange::Partial(range) => (StatusCode::PARTIAL_CONTENT, range),
+        ByteRange::Unsatisfiable => {
+            let content_range = format!("bytes */{}", size);
+            return Ok(Some(
+                (StatusCode::RANGE_NOT_SATISFIABLE, [(CONTENT_RANGE, content_range)])
+                    .into_response(),
+            ));
+        }
+    };
+    let body = match app.storage.stream(name, range.clone()).await? {
+        Some(stream) => stream,
+        None => return Ok(None),
+    };
+
+    let mut response = Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, mime)
+        .header(CONTENT_LENGTH, range.end - range.start)
+        .header(ACCEPT_RANGES, "bytes");
+    if status == StatusCode::PARTIAL_CONTENT {
+        let content_range = format!("bytes {}-{}/{}", range.start, range.end - 1, size);
+        response = response.header(CONTENT_RANGE, content_range);
+    }
+    Ok(Some(response.body(StreamBody::new(body))?.into_response()))
+}
+
 pub async fn create_post(
     State(app): State<Arc<App>>,
-    Extension(input): Extension<Result<Input, RequestError>>,
+    Path(board): Path<String>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    mut session: WritableSession,
+    headers: HeaderMap,
+    Extension(input): Extension<Result<(Input, Vec<StagedFile>), RequestError>>,
 ) -> Response {
-    match input {
-        Ok(input) => match app.models.create_post(&input).await {
-            Ok(_) => match input.parent {
-                Some(p) => Redirect::to(format!("/{}/{}", input.board, p).as_str()).into_response(),
-                None => Redirect::to(format!("/{}", input.board).as_str()).into_response(),
-            },
-            Err(e) => e.to_string().into_response(),
+    let current = match app.board(&board).await {
+        Some(current) => current,
+        None => return not_found(Path(board)).await.into_response(),
+    };
+    let config = &app.config.board;
+    let forwarded = app.config.security.forwarded_header.as_deref();
+    let ip = client_ip(&headers, forwarded, addr.ip());
+
+    let result = match input {
+        Ok((input, files)) => app
+            .models
+            .create_post(
+                &input,
+                &files,
+                &app.storage,
+                ip,
+                config.bump_limit,
+                current.capacity(config),
+            )
+            .await
+            .map(|_| input)
+            .map_err(RequestError::from),
+        Err(e) => Err(e),
+    };
+
+    match result {
+        Ok(input) => match input.parent {
+            Some(p) => Redirect::to(format!("/{}/{}", input.board, p).as_str()).into_response(),
+            None => Redirect::to(format!("/{}", input.board).as_str()).into_response(),
         },
-        Err(e) => e.to_string().into_response(),
+        Err(e) => {
+            // rendered back on the form by the page we came from
+            session.insert("flash", e.to_string()).unwrap();
+            Redirect::to(&return_path(&board, &headers)).into_response()
+        }
     }
 }
 
+/// Where a failed post sends the poster back to, the thread they replied in when they came
+/// from one. Only the thread id is taken from the referrer so it can't lead anywhere else.
+fn return_path(board: &str, headers: &HeaderMap) -> String {
+    let thread = headers
+        .get(REFERER)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.parse::<Uri>().ok())
+        .and_then(|uri| {
+            let rest = uri.path().strip_prefix(&format!("/{}/", board))?;
+            rest.trim_end_matches('/').parse::<u32>().ok()
+        });
+
+    match thread {
+        Some(id) => format!("/{}/{}", board, id),
+        None => format!("/{}/", board),
+    }
+}
+
+/// Moderation is open to staff roles only, being signed in isn't enough.
+fn is_staff(session: &Session) -> bool {
+    session.get::<Role>("role").is_some_and(|role| role.is_staff())
+}
+
+pub async fn static_error(err: std::io::Error) -> impl IntoResponse {
+    tracing::error!("failed to serve static file: {}", err);
+    StatusCode::INTERNAL_SERVER_ERROR
+}
+
 pub async fn fallback(path: Uri) -> impl IntoResponse {
     format!("Oops! No {}", path)
 }
@@ -292,3 +732,18 @@ pub async fn timeout(method: Method, uri: Uri, err: BoxError) -> impl IntoRespon
         )
     }
 }
+
+#[test]
+fn test_return_path() {
+    let referer = |value: &str| {
+        let mut headers = HeaderMap::new();
+        headers.insert(REFERER, value.parse().unwrap());
+        headers
+    };
+
+    assert_eq!(return_path("b", &HeaderMap::new()), "/b/");
+    assert_eq!(return_path("b", &referer("https://example.com/b/42")), "/b/42");
+    assert_eq!(return_path("b", &referer("/b/page/2")), "/b/");
+    assert_eq!(return_path("b", &referer("https://evil.example/b/")), "/b/");
+    assert_eq!(return_path("b", &referer("https://evil.example/g/42")), "/b/");
+}
@@ -1,48 +1,334 @@
-use std::{fs::read_to_string, time::Duration};
+use std::{
+    fs::read_to_string,
+    io::Cursor,
+    net::IpAddr,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
+use axum::http::HeaderMap;
 use axum_server::Handle;
 use base64::{engine::general_purpose, Engine};
 use chrono::Datelike;
-use color_eyre::Report;
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Report,
+};
 use digest::Digest;
-use ripemd::Ripemd160;
+use image::{DynamicImage, ImageOutputFormat};
+use mime_sniffer::MimeTypeSniffer;
+use sha2::Sha256;
 use sqlx::{Pool, Postgres};
-use tokio::{fs::File, io::AsyncWriteExt};
-use tracing::info;
+use tokio::{process::Command, task};
 
-use super::config::Config;
+use super::{
+    config::Config,
+    data::App,
+    metadata::{apply_orientation, jpeg_orientation},
+};
 
 fn current_year() -> u32 {
     chrono::Utc::now().year() as u32
 }
 
+/// Files are addressed by a hash of their full contents so identical uploads share one name.
 pub fn format_name(name: (&[u8], String)) -> String {
-    let mut hasher = Ripemd160::new();
-    hasher.update(name.0);
+    content_hash(name.0) + "." + &name.1
+}
+
+pub fn content_hash(bytes: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(bytes);
+
+    encode_hash(hasher)
+}
+
+/// Same as `content_hash` for contents that were hashed as they were received.
+pub fn encode_hash(hasher: Sha256) -> String {
+    general_purpose::URL_SAFE_NO_PAD.encode(hasher.finalize().as_slice())
+}
+
+/// Human readable size using binary multiples, as file sizes are usually displayed.
+pub fn format_size(bytes: i64) -> String {
+    match bytes {
+        b if b < 1024 => format!("{} B", b),
+        b if b < 1024 * 1024 => format!("{} KB", b / 1024),
+        b => format!("{:.1} MB", b as f64 / (1024.0 * 1024.0)),
+    }
+}
+
+pub fn format_duration(seconds: f32) -> String {
+    let seconds = seconds.round() as u64;
+
+    match seconds / 3600 {
+        0 => format!("{}:{:02}", seconds / 60, seconds % 60),
+        hours => format!("{}:{:02}:{:02}", hours, seconds / 60 % 60, seconds % 60),
+    }
+}
+
+/// Keeps the last path component of a client supplied filename, some browsers send full paths.
+pub fn clean_filename(filename: &str) -> Option<String> {
+    let name = filename.rsplit(['/', '\\']).next()?.trim();
+
+    match name.is_empty() {
+        true => None,
+        false => Some(name.chars().take(255).collect()),
+    }
+}
+
+pub fn image_dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    image::io::Reader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .ok()?
+        .into_dimensions()
+        .ok()
+}
+
+/// Difference hash of an image, similar images differ in only a few bits which survives
+/// re-encoding and resizing, unlike the content hash.
+pub fn perceptual_hash(bytes: &[u8]) -> Option<i64> {
+    let image = image::load_from_memory(bytes)
+        .ok()?
+        .resize_exact(9, 8, image::imageops::FilterType::Triangle)
+        .into_luma8();
+
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if image.get_pixel(x, y)[0] < image.get_pixel(x + 1, y)[0] {
+                hash |= 1;
+            }
+        }
+    }
+    Some(hash as i64)
+}
+
+/// Maximum amount of differing bits for two perceptual hashes to be considered the same image.
+pub const PHASH_DISTANCE: i32 = 6;
+
+/// Thumbnails are bounded to a square of this size while keeping their aspect ratio.
+pub const THUMBNAIL_SIZE: u32 = 250;
+
+/// The address of a poster, the last entry of `header` when it's set since that's the one the
+/// reverse proxy appended, the peer of the connection otherwise.
+pub fn client_ip(headers: &HeaderMap, header: Option<&str>, peer: IpAddr) -> IpAddr {
+    header
+        .and_then(|name| headers.get_all(name).iter().next_back())
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.rsplit(',').next())
+        .and_then(|ip| ip.trim().parse().ok())
+        .unwrap_or(peer)
+}
 
-    let base = general_purpose::URL_SAFE_NO_PAD.encode(hasher.finalize().as_slice());
-    base + "." + &name.1
+/// Thumbnails of JPEGs and poster frames of videos are JPEGs, other images and audio waveforms
+/// become PNGs.
+pub fn thumbnail_name(name: &str, mime: &str) -> Option<String> {
+    let stem = name.split('.').next()?;
+
+    match mime {
+        "image/jpeg" | "video/webm" | "video/mp4" => Some(format!("t_{}.jpg", stem)),
+        "image/png" | "image/webp" | "audio/mpeg" | "audio/ogg" => Some(format!("t_{}.png", stem)),
+        _ => None,
+    }
 }
 
-pub async fn save_file(name: String, bytes: &[u8]) -> Result<(), Report> {
-    info!("saving {} with length {} ...", name, bytes.len());
+/// Major brands of ISO media files that browsers play as MP4 video. HEIC images and M4A audio
+/// use the same container under brands of their own.
+const MP4_BRANDS: &[&[u8; 4]] = &[
+    b"isom", b"iso2", b"iso4", b"iso5", b"iso6", b"mp41", b"mp42", b"avc1", b"dash", b"M4V ",
+];
 
-    let mut file = File::create("./.tmp/".to_owned() + &name).await?;
-    file.write_all(bytes).await?;
+/// Enough for every signature `sniff_mime` knows about.
+pub const SNIFF_LENGTH: usize = 512;
 
-    Ok(())
+/// `mime_sniffer` only reliably recognizes images, so audio and video containers are checked
+/// by their signatures first.
+pub fn sniff_mime<T: AsRef<[u8]>>(bytes: &T) -> Option<&str> {
+    match bytes.as_ref() {
+        [0x1a, 0x45, 0xdf, 0xa3, ..] => Some("video/webm"),
+        [_, _, _, _, b'f', b't', b'y', b'p', a, b, c, d, ..] => {
+            MP4_BRANDS.contains(&&[*a, *b, *c, *d]).then_some("video/mp4")
+        }
+        [b'O', b'g', b'g', b'S', ..] => Some("audio/ogg"),
+        [b'I', b'D', b'3', ..] => Some("audio/mpeg"),
+        // MPEG audio frame sync
+        [0xff, b, ..] if b & 0xe0 == 0xe0 && b & 0x06 != 0 => Some("audio/mpeg"),
+        _ => bytes.sniff_mime_type(),
+    }
 }
 
-pub async fn hash(input: &[u8]) -> Vec<u8> {
-    let mut hasher = Ripemd160::new();
-    hasher.update(&input);
-    hasher.finalize().as_slice().to_owned()
+/// The part of a file of `size` bytes a `Range` header asks for.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ByteRange {
+    Full,
+    Partial(Range<u64>),
+    Unsatisfiable,
+}
+
+/// Only a single range is honoured, players don't ask for more. A header we don't understand
+/// is ignored and the whole file is sent, like servers are supposed to.
+pub fn parse_range(header: Option<&str>, size: u64) -> ByteRange {
+    let spec = match header.and_then(|x| x.trim().strip_prefix("bytes=")) {
+        Some(spec) if !spec.contains(',') => spec,
+        _ => return ByteRange::Full,
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some((start, end)) => (start.trim(), end.trim()),
+        None => return ByteRange::Full,
+    };
+
+    let range = match (start, end.parse::<u64>()) {
+        // the last `end` bytes
+        ("", Ok(n)) => size.saturating_sub(n)..size,
+        (start, _) => match (start.parse::<u64>(), end) {
+            (Ok(start), "") => start..size,
+            (Ok(start), end) => match end.parse::<u64>() {
+                Ok(end) if end >= start => start..size.min(end.saturating_add(1)),
+                _ => return ByteRange::Full,
+            },
+            _ => return ByteRange::Full,
+        },
+    };
+
+    match range.start < size && !range.is_empty() {
+        true => ByteRange::Partial(range),
+        false => ByteRange::Unsatisfiable,
+    }
 }
 
-pub async fn read_config() -> Result<Config, toml::de::Error> {
-    let s = read_to_string("./tokichan.toml").expect("error reading configuration file");
+pub fn file_extension(mime: &str) -> &str {
+    match mime {
+        "audio/mpeg" => "mp3",
+        _ => mime.split('/').last().unwrap_or("bin"),
+    }
+}
 
-    toml::from_str(s.as_str())
+#[derive(Debug, Default, PartialEq)]
+pub struct MediaInfo {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// In seconds.
+    pub duration: Option<f32>,
+}
+
+/// Uploads are spooled to disk while they're received, the file is removed once dropped.
+/// ffmpeg needs a real file anyway since most containers can't be read from a pipe.
+#[derive(Debug)]
+pub struct TempFile(PathBuf);
+
+impl TempFile {
+    pub async fn create() -> Result<(Self, tokio::fs::File), Report> {
+        let path = std::env::temp_dir().join(format!("tokichan-{:x}", rand::random::<u64>()));
+        let file = tokio::fs::File::create(&path).await?;
+        Ok((Self(path), file))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+pub async fn probe_media(path: &Path) -> Result<MediaInfo, Report> {
+    let output = Command::new("ffprobe")
+        .args(["-v", "error", "-show_entries", "format=duration:stream=width,height"])
+        .args(["-of", "default=noprint_wrappers=1"])
+        .arg(path)
+        .output()
+        .await
+        .wrap_err("error running ffprobe")?;
+
+    if !output.status.success() {
+        return Err(eyre!(
+            "ffprobe failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    Ok(parse_probe(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Streams come before the format section, the first video stream determines the dimensions.
+fn parse_probe(output: &str) -> MediaInfo {
+    let mut info = MediaInfo::default();
+
+    for line in output.lines() {
+        match line.split_once('=') {
+            Some(("width", value)) => info.width = info.width.or(value.parse().ok()),
+            Some(("height", value)) => info.height = info.height.or(value.parse().ok()),
+            Some(("duration", value)) => info.duration = value.parse().ok(),
+            _ => {}
+        }
+    }
+    info
+}
+
+/// Poster frame for videos and a waveform for audio, encoded like `thumbnail_name` expects.
+pub async fn create_poster(mime: &str, path: &Path) -> Result<Vec<u8>, Report> {
+    let mut command = Command::new("ffmpeg");
+    command.args(["-v", "error", "-i"]).arg(path);
+
+    match mime.starts_with("audio/") {
+        true => command
+            .args(["-filter_complex"])
+            .arg(format!("showwavespic=s={}x{}", THUMBNAIL_SIZE, THUMBNAIL_SIZE / 2))
+            .args(["-c:v", "png"]),
+        // picks a representative frame instead of a possibly black first one
+        false => command
+            .args(["-vf"])
+            .arg(format!(
+                "thumbnail,scale={0}:{0}:force_original_aspect_ratio=decrease",
+                THUMBNAIL_SIZE
+            ))
+            .args(["-c:v", "mjpeg"]),
+    };
+
+    let output = command
+        .args(["-frames:v", "1", "-f", "image2pipe", "pipe:1"])
+        .output()
+        .await
+        .wrap_err("error running ffmpeg")?;
+
+    if !output.status.success() || output.stdout.is_empty() {
+        return Err(eyre!(
+            "ffmpeg failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    Ok(output.stdout)
+}
+
+pub async fn create_thumbnail(name: &str, bytes: Vec<u8>) -> Result<Vec<u8>, Report> {
+    let thumbnail = task::spawn_blocking(move || {
+        let image = image::load_from_memory(&bytes)?;
+        let image = apply_orientation(image, jpeg_orientation(&bytes).unwrap_or(1));
+        Ok::<_, image::ImageError>(image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE))
+    })
+    .await??;
+
+    let (thumbnail, format) = match name.ends_with(".jpg") {
+        // JPEG has no alpha channel
+        true => (DynamicImage::from(thumbnail.to_rgb8()), ImageOutputFormat::Jpeg(80)),
+        false => (thumbnail, ImageOutputFormat::Png),
+    };
+
+    let mut buf = Cursor::new(Vec::new());
+    thumbnail.write_to(&mut buf, format)?;
+
+    Ok(buf.into_inner())
+}
+
+pub async fn read_config() -> Result<Config, Report> {
+    let s = read_to_string("./tokichan.toml").wrap_err("error reading configuration file")?;
+
+    Ok(toml::from_str(s.as_str())?)
 }
 
 pub async fn graceful_shutdown(handle: Handle, pool: Pool<Postgres>) {
@@ -62,3 +348,160 @@ pub async fn graceful_shutdown(handle: Handle, pool: Pool<Postgres>) {
         tracing::info!("alive connections: {}", handle.connection_count());
     }
 }
+
+/// Periodically removes captcha challenges that were never answered.
+pub async fn expire_captchas(app: Arc<App>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(app.config.captcha.lifetime.get()));
+
+    loop {
+        interval.tick().await;
+
+        match app.models.expire_captchas().await {
+            Ok(0) => {}
+            Ok(n) => tracing::debug!("expired {} captchas", n),
+            Err(e) => tracing::error!("failed to expire captchas: {}", e),
+        }
+    }
+}
+
+/// Periodically moves threads that fell off the last page of their board into the archive and
+/// forgets the addresses of old posts.
+pub async fn archive_threads(app: Arc<App>) {
+    let config = &app.config.archive;
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval.get()));
+
+    loop {
+        interval.tick().await;
+
+        for board in app.boards().await.iter() {
+            let capacity = board.capacity(&app.config.board);
+
+            if let Err(e) = app
+                .models
+                .archive_board(&board.name, capacity, config.min_age as f64)
+                .await
+            {
+                tracing::error!("failed to archive threads of /{}/: {}", board.name, e);
+            }
+        }
+
+        match app.models.forget_addresses(config.ip_retention as f64).await {
+            Ok(0) => {}
+            Ok(n) => tracing::debug!("forgot the addresses of {} posts", n),
+            Err(e) => tracing::error!("failed to forget addresses: {}", e),
+        }
+    }
+}
+
+/// A directory under the system's temporary directory that no other test uses, so tests
+/// running in parallel never step on each other.
+#[cfg(test)]
+pub fn test_dir(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("tokichan-test-{}-{:x}", name, rand::random::<u64>()))
+}
+
+#[test]
+fn test_file_info() {
+    assert_eq!(format_size(512), "512 B");
+    assert_eq!(format_size(340 * 1024 + 100), "340 KB");
+    assert_eq!(format_size(5 * 1024 * 1024 / 2), "2.5 MB");
+    assert_eq!(format_duration(42.4), "0:42");
+    assert_eq!(format_duration(3725.0), "1:02:05");
+
+    assert_eq!(clean_filename("C:\\fakepath\\cat.jpg"), Some("cat.jpg".to_owned()));
+    assert_eq!(clean_filename("photos/cat.jpg"), Some("cat.jpg".to_owned()));
+    assert_eq!(clean_filename(" "), None);
+    assert_eq!(clean_filename(&"a".repeat(300)).map(|x| x.len()), Some(255));
+}
+
+#[test]
+fn test_client_ip() {
+    use std::net::Ipv4Addr;
+
+    let peer = IpAddr::from(Ipv4Addr::LOCALHOST);
+    let mut headers = HeaderMap::new();
+    headers.insert("x-forwarded-for", "10.0.0.1, 192.0.2.7".parse().unwrap());
+
+    assert_eq!(client_ip(&headers, None, peer), peer);
+    assert_eq!(
+        client_ip(&headers, Some("X-Forwarded-For"), peer),
+        IpAddr::from(Ipv4Addr::new(192, 0, 2, 7))
+    );
+    assert_eq!(client_ip(&headers, Some("X-Real-IP"), peer), peer);
+    headers.insert("x-forwarded-for", "garbage".parse().unwrap());
+    assert_eq!(client_ip(&headers, Some("X-Forwarded-For"), peer), peer);
+}
+
+#[test]
+fn test_sniff_mime() {
+    assert_eq!(sniff_mime(&[0x1a, 0x45, 0xdf, 0xa3, 0x9f]), Some("video/webm"));
+    assert_eq!(sniff_mime(b"\0\0\0\x20ftypisom"), Some("video/mp4"));
+    assert_eq!(sniff_mime(b"\0\0\0\x18ftypheic"), None);
+    assert_eq!(sniff_mime(b"\0\0\0\x20ftypM4A "), None);
+    assert_eq!(sniff_mime(b"OggS\0\x02"), Some("audio/ogg"));
+    assert_eq!(sniff_mime(b"ID3\x04\0"), Some("audio/mpeg"));
+    assert_eq!(sniff_mime(&[0xff, 0xfb, 0x90, 0x64]), Some("audio/mpeg"));
+    assert_eq!(sniff_mime(b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR"), Some("image/png"));
+    assert_eq!(sniff_mime(b"hello"), None);
+
+    assert_eq!(file_extension("audio/mpeg"), "mp3");
+    assert_eq!(file_extension("video/webm"), "webm");
+}
+
+#[test]
+fn test_parse_range() {
+    let range = |header| parse_range(Some(header), 1000);
+
+    assert_eq!(parse_range(None, 1000), ByteRange::Full);
+    assert_eq!(range("bytes=0-499"), ByteRange::Partial(0..500));
+    assert_eq!(range("bytes=500-"), ByteRange::Partial(500..1000));
+    assert_eq!(range("bytes=-200"), ByteRange::Partial(800..1000));
+    assert_eq!(range("bytes=900-2000"), ByteRange::Partial(900..1000));
+    assert_eq!(range("bytes=-2000"), ByteRange::Partial(0..1000));
+    assert_eq!(range("bytes=1000-"), ByteRange::Unsatisfiable);
+    assert_eq!(range("bytes=-0"), ByteRange::Unsatisfiable);
+    assert_eq!(parse_range(Some("bytes=0-"), 0), ByteRange::Unsatisfiable);
+
+    // several ranges or ones we can't read get the whole file
+    assert_eq!(range("bytes=0-1,5-6"), ByteRange::Full);
+    assert_eq!(range("bytes=5-1"), ByteRange::Full);
+    assert_eq!(range("bytes=a-b"), ByteRange::Full);
+    assert_eq!(range("items=0-1"), ByteRange::Full);
+}
+
+#[test]
+fn test_parse_probe() {
+    let video = "width=1280\nheight=720\nwidth=N/A\nheight=N/A\nduration=12.480000\n";
+    assert_eq!(
+        parse_probe(video),
+        MediaInfo {
+            width: Some(1280),
+            height: Some(720),
+            duration: Some(12.48),
+        }
+    );
+
+    let audio = "duration=N/A\n";
+    assert_eq!(parse_probe(audio), MediaInfo::default());
+}
+
+#[test]
+fn test_perceptual_hash() {
+    let encode = |image: DynamicImage, format: ImageOutputFormat| {
+        let mut bytes = Cursor::new(Vec::new());
+        image.write_to(&mut bytes, format).unwrap();
+        bytes.into_inner()
+    };
+    let gradient =
+        image::RgbImage::from_fn(64, 48, |x, y| image::Rgb([(x * 4) as u8, (y * 5) as u8, 0]));
+    let original = DynamicImage::ImageRgb8(gradient);
+
+    let png = perceptual_hash(&encode(original.clone(), ImageOutputFormat::Png)).unwrap();
+    let resized = original.resize_exact(200, 150, image::imageops::FilterType::Nearest);
+    let jpeg = perceptual_hash(&encode(resized, ImageOutputFormat::Jpeg(60))).unwrap();
+    let flipped = perceptual_hash(&encode(original.fliph(), ImageOutputFormat::Png)).unwrap();
+
+    assert!((png ^ jpeg).count_ones() as i32 <= PHASH_DISTANCE);
+    assert!((png ^ flipped).count_ones() as i32 > PHASH_DISTANCE);
+    assert_eq!(perceptual_hash(b"not an image"), None);
+}
@@ -0,0 +1,370 @@
+use image::DynamicImage;
+
+/// Removes EXIF, XMP, comments and other metadata from an image without re-encoding it, so
+/// the pixels stay untouched. The EXIF orientation of a JPEG survives in a segment of its own
+/// since it decides which way up the image is shown. Returns `None` for formats we can't
+/// rewrite or malformed files.
+pub fn strip_metadata(bytes: &[u8], mime: &str) -> Option<Vec<u8>> {
+    match mime {
+        "image/jpeg" => strip_jpeg(bytes),
+        "image/png" => strip_png(bytes),
+        "image/webp" => strip_webp(bytes),
+        _ => None,
+    }
+}
+
+fn strip_jpeg(bytes: &[u8]) -> Option<Vec<u8>> {
+    if !bytes.starts_with(&[0xff, 0xd8]) {
+        return None;
+    }
+
+    let mut out = vec![0xff, 0xd8];
+    let mut pos = 2;
+
+    loop {
+        // markers may be preceded by any amount of fill bytes
+        while bytes.get(pos..pos + 2)? == [0xff, 0xff] {
+            pos += 1;
+        }
+        if bytes[pos] != 0xff {
+            return None;
+        }
+
+        let marker = bytes[pos + 1];
+        match marker {
+            // anything appended after the end of image is dropped, it's a common place to hide
+            // another EXIF blob or a whole file
+            0xd9 => {
+                out.extend_from_slice(&bytes[pos..pos + 2]);
+                return Some(out);
+            }
+            // standalone markers without a length
+            0x01 | 0xd0..=0xd7 => {
+                out.extend_from_slice(&bytes[pos..pos + 2]);
+                pos += 2;
+                continue;
+            }
+            _ => {}
+        }
+
+        let length = u16::from_be_bytes(bytes.get(pos + 2..pos + 4)?.try_into().ok()?) as usize;
+        if length < 2 {
+            return None;
+        }
+        let segment = bytes.get(pos..pos + 2 + length)?;
+
+        // of EXIF only the orientation is kept, the default one isn't worth a segment
+        if marker == 0xe1 {
+            if let Some(orientation) = exif_orientation(segment).filter(|x| *x != 1) {
+                out.extend_from_slice(&orientation_segment(orientation));
+            }
+        }
+
+        let keep = match marker {
+            // JFIF
+            0xe0 => true,
+            // color profiles and the Adobe segment change how the image is decoded
+            0xe2 => segment[4..].starts_with(b"ICC_PROFILE\0"),
+            0xee => segment[4..].starts_with(b"Adobe"),
+            // EXIF, XMP, vendor segments and comments
+            0xe1 | 0xe3..=0xed | 0xef | 0xfe => false,
+            _ => true,
+        };
+        if keep {
+            out.extend_from_slice(segment);
+        }
+        pos += segment.len();
+
+        // the entropy-coded data of a scan runs up to the next marker that isn't a stuffed 0xff
+        // or a restart, progressive images continue with more segments and scans after it
+        if marker == 0xda {
+            let start = pos;
+            while let Some(pair) = bytes.get(pos..pos + 2) {
+                if pair[0] == 0xff && !matches!(pair[1], 0x00 | 0xd0..=0xd7) {
+                    break;
+                }
+                pos += 1;
+            }
+            // without an end of image marker the rest is all image data
+            if pos + 1 >= bytes.len() {
+                out.extend_from_slice(&bytes[start..]);
+                return Some(out);
+            }
+            out.extend_from_slice(&bytes[start..pos]);
+        }
+    }
+}
+
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+const ORIENTATION_TAG: u16 = 0x0112;
+
+/// The EXIF orientation of a JPEG, from 1 to 8, `None` when it doesn't have a valid one.
+pub fn jpeg_orientation(bytes: &[u8]) -> Option<u16> {
+    if !bytes.starts_with(&[0xff, 0xd8]) {
+        return None;
+    }
+
+    let mut pos = 2;
+    loop {
+        while bytes.get(pos..pos + 2)? == [0xff, 0xff] {
+            pos += 1;
+        }
+        let marker = bytes[pos + 1];
+        if bytes[pos] != 0xff || matches!(marker, 0xda | 0xd9) {
+            return None;
+        }
+        let length = u16::from_be_bytes(bytes.get(pos + 2..pos + 4)?.try_into().ok()?) as usize;
+        let segment = bytes.get(pos..pos + 2 + length)?;
+
+        if marker == 0xe1 {
+            if let Some(orientation) = exif_orientation(segment) {
+                return Some(orientation);
+            }
+        }
+        pos += segment.len();
+    }
+}
+
+/// Reads the orientation from the first IFD of an APP1 segment, including its marker.
+fn exif_orientation(segment: &[u8]) -> Option<u16> {
+    let tiff = segment.get(4..)?.strip_prefix(EXIF_HEADER)?;
+    let little = match tiff.get(..2)? {
+        b"II" => true,
+        b"MM" => false,
+        _ => return None,
+    };
+    let u16_at = |pos: usize| {
+        let bytes = tiff.get(pos..pos + 2)?.try_into().ok()?;
+        Some(match little {
+            true => u16::from_le_bytes(bytes),
+            false => u16::from_be_bytes(bytes),
+        })
+    };
+    let u32_at = |pos: usize| {
+        let bytes = tiff.get(pos..pos + 4)?.try_into().ok()?;
+        Some(match little {
+            true => u32::from_le_bytes(bytes),
+            false => u32::from_be_bytes(bytes),
+        })
+    };
+
+    let ifd = u32_at(4)? as usize;
+    (0..u16_at(ifd)? as usize)
+        .map(|i| ifd + 2 + i * 12)
+        .find(|&entry| u16_at(entry) == Some(ORIENTATION_TAG))
+        .and_then(|entry| u16_at(entry + 8))
+        .filter(|orientation| (1..=8).contains(orientation))
+}
+
+/// A minimal APP1 segment holding nothing but `orientation`.
+fn orientation_segment(orientation: u16) -> Vec<u8> {
+    let mut tiff = b"MM\0\x2a\0\0\0\x08".to_vec();
+    // one entry: the tag, SHORT, a count of one and the value padded to four bytes
+    tiff.extend_from_slice(&1u16.to_be_bytes());
+    tiff.extend_from_slice(&ORIENTATION_TAG.to_be_bytes());
+    tiff.extend_from_slice(&3u16.to_be_bytes());
+    tiff.extend_from_slice(&1u32.to_be_bytes());
+    tiff.extend_from_slice(&orientation.to_be_bytes());
+    tiff.extend_from_slice(&[0, 0]);
+    // no further IFDs
+    tiff.extend_from_slice(&0u32.to_be_bytes());
+
+    let length = (2 + EXIF_HEADER.len() + tiff.len()) as u16;
+    [&[0xff, 0xe1][..], &length.to_be_bytes()[..], EXIF_HEADER, &tiff[..]].concat()
+}
+
+/// Turns an image the way its EXIF orientation says it should be shown.
+pub fn apply_orientation(image: DynamicImage, orientation: u16) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+fn strip_png(bytes: &[u8]) -> Option<Vec<u8>> {
+    let mut out = bytes.get(..8).filter(|x| *x == PNG_SIGNATURE)?.to_vec();
+    let mut pos = 8;
+
+    while pos < bytes.len() {
+        let length = u32::from_be_bytes(bytes.get(pos..pos + 4)?.try_into().ok()?) as usize;
+        let chunk = bytes.get(pos..pos.checked_add(length)?.checked_add(12)?)?;
+        let kind = &chunk[4..8];
+
+        // critical chunks plus the ancillary ones that affect rendering, this drops text,
+        // EXIF and timestamp chunks as well as anything private
+        let keep = kind[0].is_ascii_uppercase()
+            || matches!(
+                kind,
+                b"tRNS" | b"gAMA" | b"cHRM" | b"sRGB" | b"iCCP" | b"sBIT" | b"bKGD" | b"pHYs"
+                    | b"acTL" | b"fcTL" | b"fdAT"
+            );
+        if keep {
+            out.extend_from_slice(chunk);
+        }
+        pos += chunk.len();
+
+        if kind == b"IEND" {
+            return Some(out);
+        }
+    }
+
+    None
+}
+
+const VP8X_EXIF: u8 = 0x08;
+const VP8X_XMP: u8 = 0x04;
+
+fn strip_webp(bytes: &[u8]) -> Option<Vec<u8>> {
+    if bytes.get(..4)? != b"RIFF" || bytes.get(8..12)? != b"WEBP" {
+        return None;
+    }
+
+    let mut out = bytes[..12].to_vec();
+    let mut pos = 12;
+
+    while pos < bytes.len() {
+        let size = u32::from_le_bytes(bytes.get(pos + 4..pos + 8)?.try_into().ok()?) as usize;
+        let end = pos.checked_add(size)?.checked_add(8)?;
+        let chunk = bytes.get(pos..end)?;
+
+        let start = out.len();
+        match &chunk[..4] {
+            b"VP8 " | b"VP8L" | b"VP8X" | b"ALPH" | b"ANIM" | b"ANMF" | b"ICCP" => {
+                out.extend_from_slice(chunk);
+                // chunks are padded to an even size, some encoders omit the final padding
+                if size % 2 == 1 {
+                    out.push(0);
+                }
+            }
+            _ => {}
+        }
+        if &chunk[..4] == b"VP8X" {
+            *out.get_mut(start + 8)? &= !(VP8X_EXIF | VP8X_XMP);
+        }
+        pos = end + size % 2;
+    }
+
+    let size = u32::try_from(out.len() - 8).ok()?;
+    out[4..8].copy_from_slice(&size.to_le_bytes());
+    Some(out)
+}
+
+#[test]
+fn test_strip_jpeg() {
+    use image::{DynamicImage, ImageOutputFormat};
+    use std::io::Cursor;
+
+    let mut encoded = Cursor::new(Vec::new());
+    DynamicImage::new_rgb8(16, 16)
+        .write_to(&mut encoded, ImageOutputFormat::Jpeg(80))
+        .unwrap();
+    let encoded = encoded.into_inner();
+
+    // splice an EXIF segment and a comment right after the SOI marker
+    let mut exif = vec![0xff, 0xe1, 0x00, 0x10];
+    exif.extend_from_slice(b"Exif\0\0GPS12345");
+    exif[3] = (exif.len() - 2) as u8;
+    let comment = [0xff, 0xfe, 0x00, 0x06, b'n', b'i', b'k', b'o'];
+    let bytes = [&encoded[..2], &exif[..], &comment[..], &encoded[2..]].concat();
+
+    let stripped = strip_metadata(&bytes, "image/jpeg").unwrap();
+    assert_eq!(stripped, encoded);
+    assert!(image::load_from_memory(&stripped).is_ok());
+    assert_eq!(strip_metadata(b"not a jpeg", "image/jpeg"), None);
+
+    // trailing data after the end of image, like another EXIF segment, doesn't survive either
+    let trailer = [&encoded[..], &exif[..]].concat();
+    assert_eq!(strip_metadata(&trailer, "image/jpeg").unwrap(), encoded);
+    assert_eq!(strip_metadata(&bytes[..40], "image/jpeg"), None);
+}
+
+#[test]
+fn test_jpeg_orientation() {
+    use image::{DynamicImage, ImageOutputFormat};
+    use std::io::Cursor;
+
+    let mut encoded = Cursor::new(Vec::new());
+    DynamicImage::new_rgb8(16, 8)
+        .write_to(&mut encoded, ImageOutputFormat::Jpeg(80))
+        .unwrap();
+    let encoded = encoded.into_inner();
+
+    // a little-endian EXIF segment with a camera model next to the orientation
+    let mut tiff = b"II\x2a\0\x08\0\0\0\x02\0".to_vec();
+    tiff.extend_from_slice(&[0x10, 0x01, 2, 0, 4, 0, 0, 0, b'n', b'i', b'k', 0]);
+    tiff.extend_from_slice(&[0x12, 0x01, 3, 0, 1, 0, 0, 0, 6, 0, 0, 0]);
+    tiff.extend_from_slice(&[0; 4]);
+    let mut exif = vec![0xff, 0xe1, 0, 0];
+    exif.extend_from_slice(EXIF_HEADER);
+    exif.extend_from_slice(&tiff);
+    exif[3] = (exif.len() - 2) as u8;
+    let bytes = [&encoded[..2], &exif[..], &encoded[2..]].concat();
+
+    assert_eq!(jpeg_orientation(&bytes), Some(6));
+    assert_eq!(jpeg_orientation(&encoded), None);
+
+    // the camera model is gone but the orientation is still there
+    let stripped = strip_metadata(&bytes, "image/jpeg").unwrap();
+    assert_eq!(jpeg_orientation(&stripped), Some(6));
+    assert!(!stripped.windows(3).any(|x| x == b"nik"));
+    let image = image::load_from_memory(&stripped).unwrap();
+    let image = apply_orientation(image, jpeg_orientation(&stripped).unwrap());
+    assert_eq!((image.width(), image.height()), (8, 16));
+}
+
+#[test]
+fn test_strip_png() {
+    use image::{DynamicImage, ImageOutputFormat};
+    use std::io::Cursor;
+
+    let mut encoded = Cursor::new(Vec::new());
+    DynamicImage::new_rgba8(16, 16)
+        .write_to(&mut encoded, ImageOutputFormat::Png)
+        .unwrap();
+    let encoded = encoded.into_inner();
+
+    // chunk CRCs aren't verified, so a zeroed one is fine for the test
+    let text = [&[0, 0, 0, 8][..], &b"tEXt"[..], &b"GPS\x0012.3"[..], &[0; 4][..]].concat();
+    let ihdr_end = 8 + 25;
+    let bytes = [&encoded[..ihdr_end], &text[..], &encoded[ihdr_end..]].concat();
+
+    let stripped = strip_metadata(&bytes, "image/png").unwrap();
+    assert_eq!(stripped, encoded);
+    assert!(image::load_from_memory(&stripped).is_ok());
+    assert_eq!(strip_metadata(&bytes[..ihdr_end], "image/png"), None);
+}
+
+#[test]
+fn test_strip_webp() {
+    let chunk = |kind: &[u8], data: &[u8]| {
+        let size = (data.len() as u32).to_le_bytes();
+        let mut chunk = [kind, &size[..], data].concat();
+        if data.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    };
+    let riff = |chunks: &[Vec<u8>]| {
+        let body = chunks.concat();
+        let size = (body.len() as u32 + 4).to_le_bytes();
+        [&b"RIFF"[..], &size[..], &b"WEBP"[..], &body[..]].concat()
+    };
+
+    let vp8x = chunk(b"VP8X", &[VP8X_EXIF | VP8X_XMP | 0x10, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    let image = chunk(b"VP8L", &[0x2f, 1, 2]);
+    let exif = chunk(b"EXIF", b"GPS12345");
+    let xmp = chunk(b"XMP ", b"<x:xmpmeta/>");
+
+    let stripped = strip_metadata(&riff(&[vp8x, image.clone(), exif, xmp]), "image/webp");
+    let expected = riff(&[chunk(b"VP8X", &[0x10, 0, 0, 0, 0, 0, 0, 0, 0, 0]), image]);
+    assert_eq!(stripped, Some(expected));
+    assert_eq!(strip_metadata(b"RIFF\0\0\0\0WAVE", "image/webp"), None);
+}
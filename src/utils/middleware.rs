@@ -1,9 +1,9 @@
 use axum::body::Body;
-use http_body::Limited;
 use std::hash::Hasher;
+use std::net::SocketAddr;
 use std::sync::Arc;
 
-use axum::extract::State;
+use axum::extract::{ConnectInfo, Path, State};
 use axum::http::header::SET_COOKIE;
 use axum::http::HeaderValue;
 use axum::http::StatusCode;
@@ -11,21 +11,20 @@ use axum::response::IntoResponse;
 use axum::{http::Request, middleware::Next, response::Response};
 use axum::{Extension, RequestExt};
 use axum_extra::extract::cookie::Cookie;
-use axum_sessions::extractors::WritableSession;
 use axum_sessions::{SameSite, SessionHandle};
 use hmac::Mac;
 use hyper::header::{CONTENT_TYPE, COOKIE};
 
-use ripemd::{Digest, Ripemd160};
 
 use tokio::sync::RwLock;
-use tracing::info;
+use tracing::{error, info};
 
 use crate::utils::error::RequestError;
-use crate::utils::helpers::hash;
+use crate::utils::helpers::client_ip;
 use crate::App;
 
-use super::captcha::CaptchaService;
+use super::captcha::{CaptchaService, TextCaptcha};
+use super::data::Role;
 use super::error::AppError;
 use super::templates::Input;
 
@@ -55,30 +54,34 @@ where
     Ok(next.run(req).await)
 }
 
-pub async fn captcha_cookie<B>(
-    Extension(cs): Extension<Arc<RwLock<CaptchaService>>>,
-    mut request: Request<B>,
-    next: Next<B>,
-) -> Response {
-    let cs = cs.read().await;
-    let captcha = cs.recv().await;
-
-    let mut hasher = Ripemd160::new();
-    hasher.update(captcha.0);
-    let hash = hasher.finalize();
-
-    let result = base64::encode(hash);
-    info!("generated captcha hash: {}", result);
+/// Stores a challenge for `solution` and returns the cookie naming it.
+async fn issue_captcha(
+    app: &App,
+    solution: &str,
+    ignore_case: bool,
+) -> Result<Cookie<'static>, Response> {
+    // the cookie only names the challenge, the solution never leaves the server
+    let id = hex::encode(rand::random::<[u8; 20]>());
+    let lifetime = app.config.captcha.lifetime.get() as f64;
+    if let Err(e) = app
+        .models
+        .create_captcha(&id, solution, ignore_case, lifetime)
+        .await
+    {
+        error!("failed to store captcha: {}", e);
+        return Err(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+    }
+    info!("issued captcha {}", id);
 
-    let cookie = Cookie::build("captcha", result)
+    Ok(Cookie::build("captcha", id)
         .path("/")
         .same_site(SameSite::Strict)
         .secure(true)
         .http_only(true)
-        .finish();
-
-    request.extensions_mut().insert(captcha.1);
+        .finish())
+}
 
+async fn with_cookie<B>(request: Request<B>, next: Next<B>, cookie: Cookie<'_>) -> Response {
     let mut response = next.run(request).await;
 
     response.headers_mut().append(
@@ -89,24 +92,87 @@ pub async fn captcha_cookie<B>(
     response
 }
 
+pub async fn captcha_cookie<B>(
+    State(app): State<Arc<App>>,
+    Extension(cs): Extension<Arc<RwLock<CaptchaService>>>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let cs = cs.read().await;
+    let captcha = cs.recv().await;
+
+    let cookie = match issue_captcha(&app, &captcha.0, false).await {
+        Ok(cookie) => cookie,
+        Err(response) => return response,
+    };
+    request.extensions_mut().insert(captcha.1);
+
+    with_cookie(request, next, cookie).await
+}
+
+/// Same as `captcha_cookie` but for a `TextCaptcha`, answers go through `parse_fields` all
+/// the same. Not found unless questions are configured.
+pub async fn question_cookie<B>(
+    State(app): State<Arc<App>>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let questions = &app.config.captcha.questions;
+    let captcha = match TextCaptcha::choose(questions, &mut rand::thread_rng()) {
+        Some(captcha) => captcha,
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+
+    let cookie = match issue_captcha(&app, &captcha.solution, true).await {
+        Ok(cookie) => cookie,
+        Err(response) => return response,
+    };
+    request.extensions_mut().insert(captcha);
+
+    with_cookie(request, next, cookie).await
+}
+
 pub async fn parse_fields(
     State(app): State<Arc<App>>,
-    _session: WritableSession,
+    Path(board): Path<String>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(session_handle): Extension<SessionHandle>,
     request: Request<Body>,
     next: Next<Body>,
 ) -> Result<impl IntoResponse, Response> {
-    let (mut parts, body) = request.into_parts();
+    // the handler writes to the same session, so no lock is held past this point
+    let role = session_handle.read().await.get::<Role>("role");
 
-    let bytes = hyper::body::to_bytes(body)
+    let board = app
+        .board(&board)
         .await
-        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response())?;
-
-    let header = parts.headers.get(CONTENT_TYPE).unwrap();
+        .ok_or_else(|| StatusCode::NOT_FOUND.into_response())?;
 
-    let boundary = multer::parse_boundary(header.to_str().unwrap()).unwrap();
-    let multipart = multer::Multipart::with_reader(&*bytes, boundary);
+    let policy = board.upload_policy(&app.config.security);
+    let (mut parts, body) = request.into_parts();
+    let forwarded = app.config.security.forwarded_header.as_deref();
+    let ip = client_ip(&parts.headers, forwarded, addr.ip());
 
-    let input = app.models.parse_fields(multipart).await.unwrap();
+    let content_type = parts
+        .headers
+        .get(CONTENT_TYPE)
+        .and_then(|header| header.to_str().ok())
+        .unwrap_or_default();
+
+    let input = match multer::parse_boundary(content_type) {
+        Ok(boundary) => {
+            // fields are streamed, anything past the limit is rejected without being buffered
+            let constraints = multer::Constraints::new()
+                .size_limit(multer::SizeLimit::new().whole_stream(policy.body_limit() as u64));
+            let multipart = multer::Multipart::with_constraints(body, boundary, constraints);
+
+            app.models
+                .parse_fields(multipart, &board, &policy)
+                .await
+                .map_err(RequestError::from)
+        }
+        Err(e) => Err(RequestError::Malformed(e.to_string())),
+    };
 
     let captcha = parts
         .headers
@@ -117,25 +183,62 @@ pub async fn parse_fields(
         .filter_map(|header| Cookie::parse_encoded(header.trim()).ok())
         .find(|cookie| cookie.name() == "captcha");
 
-    let expected_hash = hash(input.captcha.as_bytes()).await;
-    info!(
-        "received captcha: {}, captcha hash: {}",
-        &input.captcha,
-        base64::encode(expected_hash.clone())
-    );
-
-    let request = match captcha {
-        Some(captcha) if captcha.value() == base64::encode(expected_hash) => {
-            parts.extensions.insert(Ok::<Input, RequestError>(input));
-            Request::from_parts(parts, hyper::Body::from(bytes.clone()))
-        }
-        _ => {
-            parts
-                .extensions
-                .insert(Err::<Input, RequestError>(RequestError::IncorrectCaptcha));
-            Request::from_parts(parts, hyper::Body::from(bytes.clone()))
+    // received files are only processed once the challenge passes, their temporary copies are
+    // dropped otherwise
+    let input = match input {
+        Ok((input, files)) => {
+            let required = app
+                .models
+                .captcha_required(
+                    board.captcha_policy(&app.config.captcha),
+                    role.as_ref(),
+                    input.parent,
+                    ip,
+                    app.config.captcha.window,
+                )
+                .await
+                .map_err(RequestError::from);
+
+            let pow_difficulty = board.pow_difficulty(&app.config.captcha);
+            let solved = match (required, captcha) {
+                (Ok(false), _) => Ok(true),
+                (Err(e), _) => Err(e),
+                // a proof of work stands in for the captcha on boards that accept one, elsewhere
+                // it's ignored and the captcha is checked as usual
+                (Ok(true), _) if !input.pow.is_empty() && pow_difficulty > 0 => {
+                    match input.pow.split_once(':') {
+                        Some((nonce, counter)) => app
+                            .models
+                            .consume_pow(nonce, counter, pow_difficulty)
+                            .await
+                            .map_err(RequestError::from),
+                        None => Ok(false),
+                    }
+                }
+                (Ok(true), Some(captcha)) => app
+                    .models
+                    .consume_captcha(captcha.value(), &input.captcha)
+                    .await
+                    .map_err(RequestError::from),
+                (Ok(true), None) => Ok(false),
+            };
+
+            match solved {
+                Ok(true) => app
+                    .models
+                    .stage_files(files, &policy)
+                    .await
+                    .map(|staged| (input, staged))
+                    .map_err(RequestError::from),
+                Ok(false) => Err(RequestError::IncorrectCaptcha),
+                Err(e) => Err(e),
+            }
         }
+        Err(e) => Err(e),
     };
 
+    parts.extensions.insert(input);
+    let request = Request::from_parts(parts, Body::empty());
+
     Ok(next.run(request).await)
 }
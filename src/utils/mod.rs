@@ -6,8 +6,10 @@ pub mod fake;
 pub mod form;
 pub mod handlers;
 pub mod helpers;
+pub mod metadata;
 pub mod middleware;
 pub mod models;
 pub mod psql;
 pub mod routes;
+pub mod storage;
 pub mod templates;
@@ -1,30 +1,219 @@
-use std::io::Error;
-
+use crate::utils::captcha::verify_pow;
+use crate::utils::config::CaptchaPolicy;
 use crate::utils::error::RequestError;
-use crate::utils::helpers::{format_name, save_file};
+use crate::utils::helpers::{
+    clean_filename, content_hash, create_poster, create_thumbnail, encode_hash, file_extension,
+    image_dimensions, perceptual_hash, probe_media, sniff_mime, thumbnail_name, MediaInfo,
+    TempFile, PHASH_DISTANCE, SNIFF_LENGTH,
+};
+use crate::utils::metadata::strip_metadata;
+use crate::utils::storage::Storage;
 
 use super::data::*;
-use super::error::LoginError;
+use super::error::{BoardError, LoginError};
 use super::templates::Input;
 use axum::response::Redirect;
 use axum_sessions::extractors::WritableSession;
 use bevy_reflect::GetField;
 
-use color_eyre::{Report, Result};
-
-use mime_sniffer::MimeTypeSniffer;
-use password_hash::rand_core::OsRng;
-use password_hash::SaltString;
-use pbkdf2::password_hash::PasswordVerifier;
-use pbkdf2::Pbkdf2;
+use color_eyre::{eyre::eyre, Report, Result};
 
 use ripemd::Digest;
-use sqlx::PgPool;
+use sqlx::types::Json;
+use sqlx::{PgExecutor, PgPool, Postgres, Transaction};
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::sync::Arc;
 
 use thiserror::Error;
-use tokio::task;
+use sha2::Sha256;
+use tokio::io::AsyncWriteExt;
+
+use tracing::{info, warn};
+
+fn validate_board(board: &BoardForm) -> Result<(), BoardError> {
+    if board.name.chars().count() != 1 || board.name.contains('/') {
+        return Err(BoardError::InvalidName);
+    }
+    if board.title.is_empty() || board.title.chars().count() >= 64 {
+        return Err(BoardError::InvalidTitle);
+    }
+    Ok(())
+}
+
+async fn read_text(field: &mut multer::Field<'_>, key: &str) -> Result<String> {
+    let mut value = Vec::new();
+
+    while let Some(chunk) = field.chunk().await? {
+        if value.len() + chunk.len() > TEXT_FIELD_LIMIT {
+            return Err(RequestError::SizeLimit.into());
+        }
+        value.extend_from_slice(&chunk);
+    }
+
+    String::from_utf8(value)
+        .map_err(|_| RequestError::Malformed(format!("field {} is not valid UTF-8", key)).into())
+}
+
+/// A file field spooled to disk, hashed as it arrives.
+struct Upload {
+    file: TempFile,
+    size: usize,
+    hasher: Sha256,
+    /// Leading bytes to sniff the type from.
+    head: Vec<u8>,
+}
+
+/// An upload of an allowed type and size that nothing looked into past its first bytes yet,
+/// decoding and probing it waits for `stage_files` so unsolved challenges cost us little.
+#[derive(Debug)]
+pub struct ReceivedFile {
+    file: TempFile,
+    size: usize,
+    hasher: Sha256,
+    mime: String,
+    filename: Option<String>,
+    spoiler: bool,
+}
+
+/// An upload that passed every check but isn't stored anywhere yet, it's only committed once
+/// the poster solved their challenge and the temporary file goes away with the last clone.
+#[derive(Clone, Debug)]
+pub struct StagedFile {
+    pub info: FileInfo,
+    /// The contents as they'll be stored, images already have their metadata stripped.
+    pub file: Arc<TempFile>,
+    pub spoiler: bool,
+}
+
+async fn receive_file(field: &mut multer::Field<'_>, limit: usize) -> Result<Upload> {
+    let (file, mut out) = TempFile::create().await?;
+    let mut upload = Upload {
+        file,
+        size: 0,
+        hasher: Sha256::new(),
+        head: Vec::new(),
+    };
+
+    while let Some(chunk) = field.chunk().await? {
+        upload.size += chunk.len();
+        if upload.size > limit {
+            return Err(RequestError::SizeLimit.into());
+        }
+
+        let missing = SNIFF_LENGTH.saturating_sub(upload.head.len()).min(chunk.len());
+        upload.head.extend_from_slice(&chunk[..missing]);
+        upload.hasher.update(&chunk);
+        out.write_all(&chunk).await?;
+    }
+    out.flush().await?;
+
+    Ok(upload)
+}
 
-use tracing::info;
+/// Archives the threads past `capacity`, only those that haven't been bumped for `min_age`
+/// seconds when given.
+async fn archive_overflow(
+    executor: impl PgExecutor<'_>,
+    board: &str,
+    capacity: i64,
+    min_age: Option<f64>,
+) -> Result<u64> {
+    let result = sqlx::query!(
+        r#"
+            WITH overflow AS (
+                SELECT id, sticky, bumped FROM posts
+                WHERE parent IS NULL AND board = $1 AND deleted_at IS NULL
+                AND archived_at IS NULL
+                ORDER BY sticky DESC, bumped DESC, id DESC OFFSET $2
+            )
+            UPDATE posts SET archived_at = now()
+            WHERE id IN (
+                SELECT id FROM overflow
+                WHERE NOT sticky
+                AND ($3::float8 IS NULL OR bumped < now() - make_interval(secs => $3))
+            )
+            "#,
+        board,
+        capacity,
+        min_age,
+    )
+    .execute(executor)
+    .await?;
+
+    if result.rows_affected() > 0 {
+        info!("archived {} threads from /{}/", result.rows_affected(), board);
+    }
+    Ok(result.rows_affected())
+}
+
+/// Records a staged file and makes sure its contents are in storage, identical contents are
+/// stored once but put again when the object went missing. Thumbnails are taken care of by
+/// `store_thumbnails` beforehand.
+async fn store_file(
+    tx: &mut Transaction<'_, Postgres>,
+    staged: &StagedFile,
+    storage: &Arc<dyn Storage>,
+) -> Result<()> {
+    let info = &staged.info;
+    let result = sqlx::query!(
+        r#"
+            INSERT INTO files(name, hash, mime, size, width, height, filename, thumbnail, phash,
+            duration)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+            ON CONFLICT (hash) DO NOTHING
+            "#,
+        info.name,
+        info.hash,
+        info.mime,
+        info.size,
+        info.width,
+        info.height,
+        info.filename,
+        info.thumbnail,
+        info.phash,
+        info.duration,
+    )
+    .execute(&mut *tx)
+    .await?;
+    let known = result.rows_affected() == 0;
+
+    if !known || !storage.exists(&info.name).await? {
+        storage.put_file(&info.name, staged.file.path()).await?;
+    }
+
+    Ok(())
+}
+
+/// Puts the thumbnails of `files` into storage before any post refers to them, a thumbnail
+/// that can't be generated is dropped from the file instead of leaving a reference to an
+/// object that never shows up.
+async fn store_thumbnails(files: &mut [StagedFile], storage: &Arc<dyn Storage>) -> Result<()> {
+    for staged in files.iter_mut() {
+        let thumbnail = match &staged.info.thumbnail {
+            Some(thumbnail) => thumbnail.clone(),
+            None => continue,
+        };
+        if storage.exists(&thumbnail).await? {
+            continue;
+        }
+
+        let (info, path) = (&staged.info, staged.file.path());
+        let generated = async {
+            let bytes = match info.mime.starts_with("image/") {
+                true => create_thumbnail(&thumbnail, tokio::fs::read(path).await?).await?,
+                false => create_poster(&info.mime, path).await?,
+            };
+            storage.put(&thumbnail, &bytes).await
+        };
+        if let Err(e) = generated.await {
+            warn!("failed to create thumbnail {}: {}", thumbnail, e);
+            staged.info.thumbnail = None;
+        }
+    }
+
+    Ok(())
+}
 
 #[derive(Error, Debug)]
 pub enum InputError {
@@ -49,36 +238,128 @@ impl PoolModel {
         Ok(result.iter().map(|x| x.name.clone()).collect::<Vec<_>>())
     }
 
-    pub async fn get_board(&self, board: String) -> Vec<Post> {
+    pub async fn get_board(
+        &self,
+        board: String,
+        page: i64,
+        per_page: i64,
+        staff: bool,
+    ) -> Vec<Post> {
         sqlx::query_as!(
             Post,
             r#"
-             SELECT id, parent, board, created, op, email, body, subject, files FROM posts
-             WHERE parent IS NULL AND board = $1
+             SELECT id, parent, board, created, op, email, body, subject,
+             a.files AS "files?: Json<Vec<Attachment>>",
+             deleted_at, deleted_by, sticky, locked, autosage, archived_at FROM posts
+             LEFT JOIN post_attachments a ON a.post = posts.id
+             WHERE parent IS NULL AND board = $1 AND ($2 OR deleted_at IS NULL)
+             AND archived_at IS NULL
 
-             ORDER BY created DESC LIMIT 100
+             ORDER BY sticky DESC, bumped DESC, id DESC LIMIT $3 OFFSET $4
          "#,
             board,
+            staff,
+            per_page,
+            page * per_page,
         )
         .fetch_all(&self.pool)
         .await
         .expect("Oops")
     }
 
-    pub async fn recent(&self) -> Vec<Post> {
+    pub async fn count_threads(&self, board: &str, staff: bool) -> i64 {
+        sqlx::query_scalar!(
+            r#"
+             SELECT count(*) as "count!" FROM posts
+             WHERE parent IS NULL AND board = $1 AND ($2 OR deleted_at IS NULL)
+             AND archived_at IS NULL
+         "#,
+            board,
+            staff,
+        )
+        .fetch_one(&self.pool)
+        .await
+        .expect("Oops")
+    }
+
+    pub async fn recent(&self, limit: i64, staff: bool) -> Vec<Post> {
         sqlx::query_as!(
             Post,
             r#"
-             SELECT id, parent, board, created, op, email, body, subject, files FROM posts
-             WHERE parent IS NULL
-             ORDER BY created DESC LIMIT 100
+             SELECT id, parent, board, created, op, email, body, subject,
+             a.files AS "files?: Json<Vec<Attachment>>",
+             deleted_at, deleted_by, sticky, locked, autosage, archived_at FROM posts
+             LEFT JOIN post_attachments a ON a.post = posts.id
+             WHERE parent IS NULL AND ($1 OR deleted_at IS NULL) AND archived_at IS NULL
+             ORDER BY bumped DESC, id DESC LIMIT $2
         "#,
+            staff,
+            limit,
         )
         .fetch_all(&self.pool)
         .await
         .expect("Oops")
     }
 
+    pub async fn get_archive(
+        &self,
+        board: String,
+        page: i64,
+        per_page: i64,
+        staff: bool,
+    ) -> Vec<Post> {
+        sqlx::query_as!(
+            Post,
+            r#"
+             SELECT id, parent, board, created, op, email, body, subject,
+             a.files AS "files?: Json<Vec<Attachment>>",
+             deleted_at, deleted_by, sticky, locked, autosage, archived_at FROM posts
+             LEFT JOIN post_attachments a ON a.post = posts.id
+             WHERE parent IS NULL AND board = $1 AND ($2 OR deleted_at IS NULL)
+             AND archived_at IS NOT NULL
+
+             ORDER BY archived_at DESC, id DESC LIMIT $3 OFFSET $4
+         "#,
+            board,
+            staff,
+            per_page,
+            page * per_page,
+        )
+        .fetch_all(&self.pool)
+        .await
+        .expect("Oops")
+    }
+
+    pub async fn count_archived(&self, board: &str, staff: bool) -> i64 {
+        sqlx::query_scalar!(
+            r#"
+             SELECT count(*) as "count!" FROM posts
+             WHERE parent IS NULL AND board = $1 AND ($2 OR deleted_at IS NULL)
+             AND archived_at IS NOT NULL
+         "#,
+            board,
+            staff,
+        )
+        .fetch_one(&self.pool)
+        .await
+        .expect("Oops")
+    }
+
+    /// Archives every thread that fell off the last page of a board and hasn't been bumped for
+    /// at least `min_age` seconds, stickies are never archived.
+    pub async fn archive_board(&self, board: &str, capacity: i64, min_age: f64) -> Result<u64> {
+        archive_overflow(&self.pool, board, capacity, Some(min_age)).await
+    }
+
+    /// Whether any account exists, the first one is created without an admin signed in.
+    pub async fn has_users(&self) -> Result<bool> {
+        let exists = sqlx::query_scalar!(r#"SELECT EXISTS(SELECT 1 FROM users) AS "exists!""#)
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(exists)
+    }
+
     pub async fn signup(&self, credentials: Credentials) -> Result<()> {
         if credentials.username.is_empty() {
             return Err(LoginError::EmptyUsername.into());
@@ -86,21 +367,21 @@ impl PoolModel {
             return Err(LoginError::EmptyPassword.into());
         }
 
-        let salt = SaltString::generate(&mut OsRng).to_string();
+        let hash = credentials
+            .hash()
+            .map_err(|e| eyre!("failed to hash password: {}", e))?;
 
         sqlx::query!(
             r#"
-                     INSERT INTO users(name, password, salt, role)
-                     VALUES ($1, $2, $3, $4)
+                     INSERT INTO users(name, password, role)
+                     VALUES ($1, $2, $3)
                 "#,
             credentials.username,
-            credentials.hash().to_string(),
-            salt,
-            format!("{:?}", credentials.role).to_lowercase(),
+            hash,
+            credentials.role.as_ref().unwrap_or(&Role::User).as_str(),
         )
         .execute(&self.pool)
-        .await
-        .expect("Oops");
+        .await?;
         Ok(())
     }
 
@@ -111,7 +392,7 @@ impl PoolModel {
         Redirect::to("/")
     }
 
-    pub async fn login(&self, credentials: Credentials) -> Result<i32, LoginError> {
+    pub async fn login(&self, credentials: Credentials) -> Result<(i32, Role), LoginError> {
         if credentials.username.is_empty() {
             return Err(LoginError::EmptyUsername);
         } else if credentials.password.is_empty() {
@@ -120,7 +401,7 @@ impl PoolModel {
 
         let result = sqlx::query!(
             r#"
-             SELECT id, password, salt FROM users where name = $1
+             SELECT id, password, role FROM users where name = $1
         "#,
             credentials.username,
         )
@@ -130,44 +411,43 @@ impl PoolModel {
 
         match result {
             None => Err(LoginError::NonExistentUser(credentials.username)),
-            Some(record) => {
-                let expected_credentials = Credentials {
-                    password: record.password,
-                    salt: record.salt,
-                    ..Default::default()
-                };
-
-                match Pbkdf2.verify_password(
-                    credentials.hash().to_string().as_bytes(),
-                    &expected_credentials.hash(),
-                ) {
-                    Ok(_) => Ok(record.id),
-                    Err(_) => Err(LoginError::InvalidCredentials),
-                }
+            Some(record) if credentials.verify(&record.password) => {
+                Ok((record.id, record.role.parse()?))
             }
+            Some(_) => Err(LoginError::InvalidCredentials),
         }
     }
 
-    pub async fn get_post(&self, id: i32) -> Post {
+    pub async fn get_post(&self, id: i32, staff: bool) -> Option<Post> {
         sqlx::query_as!(
                 Post,
                 r#"
-                 SELECT id, parent, board, created, op, email, body, subject, files FROM posts where id = $1
+                 SELECT id, parent, board, created, op, email, body, subject,
+                 a.files AS "files?: Json<Vec<Attachment>>",
+                 deleted_at, deleted_by, sticky, locked, autosage, archived_at FROM posts
+                 LEFT JOIN post_attachments a ON a.post = posts.id
+                 WHERE id = $1 AND ($2 OR deleted_at IS NULL)
             "#,
                 id,
+                staff,
             )
-            .fetch_one(&self.pool)
+            .fetch_optional(&self.pool)
             .await
             .expect("Oops")
     }
 
-    pub async fn children(&self, parent: i32) -> Option<Vec<Post>> {
+    pub async fn children(&self, parent: i32, staff: bool) -> Option<Vec<Post>> {
         let children = sqlx::query_as!(
                 Post,
                 r#"
-                 SELECT id, parent, board, created, op, email, body, subject, files FROM posts where parent = $1
+                 SELECT id, parent, board, created, op, email, body, subject,
+                 a.files AS "files?: Json<Vec<Attachment>>",
+                 deleted_at, deleted_by, sticky, locked, autosage, archived_at FROM posts
+                 LEFT JOIN post_attachments a ON a.post = posts.id
+                 WHERE parent = $1 AND ($2 OR deleted_at IS NULL)
             "#,
                 parent,
+                staff,
             )
             .fetch_all(&self.pool)
             .await
@@ -179,11 +459,91 @@ impl PoolModel {
         }
     }
 
-    pub async fn create_post(&self, input: &Input) -> Result<(), Error> {
+    /// Hides a thread along with all of its replies, `deleted_by` refers to the staff member.
+    pub async fn delete_thread(&self, id: i32, deleted_by: i32) -> Result<()> {
+        sqlx::query!(
+            r#"
+                UPDATE posts SET deleted_at = now(), deleted_by = $2
+                WHERE (id = $1 OR parent = $1) AND deleted_at IS NULL
+                "#,
+            id,
+            deleted_by,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        info!("thread {} deleted by user {}", id, deleted_by);
+        Ok(())
+    }
+
+    pub async fn restore_thread(&self, id: i32) -> Result<()> {
         sqlx::query!(
             r#"
-                     INSERT INTO posts(board, parent, op, email, body, subject, files)
+                UPDATE posts SET deleted_at = NULL, deleted_by = NULL
+                WHERE id = $1 OR parent = $1
+                "#,
+            id,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        info!("thread {} restored", id);
+        Ok(())
+    }
+
+    /// Replies bump their thread unless they are sage, the thread is autosaged or the thread
+    /// already has more than `bump_limit` live replies. New threads push the ones past
+    /// `capacity` into the archive right away. `files` are stored and linked to the post as
+    /// part of the same transaction, their thumbnails are in storage before the post is.
+    pub async fn create_post(
+        &self,
+        input: &Input,
+        files: &[StagedFile],
+        storage: &Arc<dyn Storage>,
+        ip: IpAddr,
+        bump_limit: i64,
+        capacity: i64,
+    ) -> Result<()> {
+        // generated ahead of the transaction so nothing waits on ffmpeg while holding a lock
+        let mut files = files.to_vec();
+        store_thumbnails(&mut files, storage).await?;
+
+        let mut tx = self.pool.begin().await?;
+
+        if let Some(parent) = input.parent {
+            // the lock keeps concurrent replies from racing past the bump limit
+            let thread = sqlx::query!(
+                r#"
+                    SELECT board, parent, locked, deleted_at, archived_at FROM posts WHERE id = $1
+                    FOR UPDATE
+                    "#,
+                parent,
+            )
+            .fetch_optional(&mut tx)
+            .await?;
+
+            match thread {
+                // replies can only go to live threads of the board they're posted on
+                None => return Err(RequestError::NonExistentThread(parent).into()),
+                Some(t) if t.parent.is_some() || t.board != input.board => {
+                    return Err(RequestError::NonExistentThread(parent).into())
+                }
+                Some(t) if t.deleted_at.is_some() => {
+                    return Err(RequestError::NonExistentThread(parent).into())
+                }
+                Some(t) if t.archived_at.is_some() => {
+                    return Err(RequestError::ArchivedThread.into())
+                }
+                Some(t) if t.locked => return Err(RequestError::LockedThread.into()),
+                _ => {}
+            }
+        }
+
+        let id = sqlx::query_scalar!(
+            r#"
+                     INSERT INTO posts(board, parent, op, email, body, subject, ip)
                      VALUES ($1, $2, $3, $4, $5, $6, $7)
+                     RETURNING id
                 "#,
             input.board,
             input.parent,
@@ -191,11 +551,49 @@ impl PoolModel {
             input.email,
             input.body,
             input.subject,
-            input.files.as_deref(),
+            ip.to_string(),
         )
-        .execute(&self.pool)
-        .await
-        .expect("Oops");
+        .fetch_one(&mut tx)
+        .await?;
+
+        for (position, staged) in files.iter().enumerate() {
+            store_file(&mut tx, staged, storage).await?;
+            sqlx::query!(
+                r#"
+                    INSERT INTO post_files(post, file, position, filename, spoiler)
+                    VALUES ($1, $2, $3, $4, $5)
+                    "#,
+                id,
+                staged.info.hash,
+                position as i16,
+                staged.info.filename,
+                staged.spoiler,
+            )
+            .execute(&mut tx)
+            .await?;
+        }
+
+        match input.parent {
+            Some(parent) if !input.email.eq_ignore_ascii_case("sage") => {
+                sqlx::query!(
+                    r#"
+                        UPDATE posts SET bumped = now()
+                        WHERE id = $1 AND NOT autosage
+                        AND (
+                            SELECT count(*) FROM posts WHERE parent = $1 AND deleted_at IS NULL
+                        ) <= $2
+                        "#,
+                    parent,
+                    bump_limit,
+                )
+                .execute(&mut tx)
+                .await?;
+            }
+            None => {
+                archive_overflow(&mut tx, &input.board, capacity, None).await?;
+            }
+            _ => {}
+        }
 
         sqlx::query!(
             r#"
@@ -203,9 +601,127 @@ impl PoolModel {
                 "#,
             input.board
         )
+        .execute(&mut tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    pub async fn edit_thread(&self, flags: &EditThread) -> Result<()> {
+        sqlx::query!(
+            r#"
+                UPDATE posts SET sticky = $2, locked = $3, autosage = $4
+                WHERE id = $1 AND parent IS NULL
+                "#,
+            flags.edit_thread,
+            flags.sticky,
+            flags.locked,
+            flags.autosage,
+        )
         .execute(&self.pool)
-        .await
-        .expect("Oops");
+        .await?;
+
+        info!("thread {} edited: {:?}", flags.edit_thread, flags);
+        Ok(())
+    }
+
+    pub async fn create_board(&self, board: &BoardForm) -> Result<()> {
+        validate_board(board)?;
+
+        let result = sqlx::query!(
+            r#"
+                INSERT INTO boards(name, title) VALUES ($1, $2)
+                ON CONFLICT (name) DO NOTHING
+                "#,
+            board.name,
+            board.title,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        match result.rows_affected() {
+            0 => Err(BoardError::Exists(board.name.clone()).into()),
+            _ => {
+                info!("created board /{}/ - {}", board.name, board.title);
+                Ok(())
+            }
+        }
+    }
+
+    pub async fn rename_board(&self, board: &BoardForm) -> Result<()> {
+        validate_board(board)?;
+
+        let result = sqlx::query!(
+            r#"
+                UPDATE boards SET title = $2 WHERE name = $1
+                "#,
+            board.name,
+            board.title,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        match result.rows_affected() {
+            0 => Err(BoardError::NonExistent(board.name.clone()).into()),
+            _ => {
+                info!("renamed board /{}/ to {}", board.name, board.title);
+                Ok(())
+            }
+        }
+    }
+
+    /// Removes a board from the navigation, its posts are kept around.
+    pub async fn delete_board(&self, name: &str) -> Result<()> {
+        let result = sqlx::query!(
+            r#"
+                DELETE FROM boards WHERE name = $1
+                "#,
+            name,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        match result.rows_affected() {
+            0 => Err(BoardError::NonExistent(name.to_owned()).into()),
+            _ => {
+                info!("deleted board /{}/", name);
+                Ok(())
+            }
+        }
+    }
+
+    /// Creates the boards declared in the configuration file that are missing from the database,
+    /// boards that only exist in the database are either reported or removed.
+    pub async fn reconcile_boards(&self, boards: &[(String, String)], prune: bool) -> Result<()> {
+        for (name, title) in boards {
+            let board = BoardForm {
+                name: name.clone(),
+                title: title.clone(),
+            };
+
+            match self.create_board(&board).await {
+                Ok(_) => {}
+                Err(e) if matches!(e.downcast_ref::<BoardError>(), Some(BoardError::Exists(_))) => {}
+                Err(e) => {
+                    return Err(e.wrap_err(format!("invalid board /{}/ in configuration", name)))
+                }
+            }
+        }
+
+        let unlisted = self
+            .get_boards()
+            .await
+            .into_iter()
+            .filter(|x| !boards.iter().any(|(name, _)| *name == x.name));
+
+        for board in unlisted {
+            if prune {
+                self.delete_board(&board.name).await?;
+            } else {
+                warn!("board /{}/ is not declared in the configuration file", board.name);
+            }
+        }
 
         Ok(())
     }
@@ -214,7 +730,10 @@ impl PoolModel {
         sqlx::query_as!(
             Board,
             r#"
-                 SELECT name, title FROM boards
+                 SELECT name, title, threads_per_page, max_pages,
+                 max_file_size, allowed_mimes, max_files, default_name, text_only, nsfw,
+                 keep_originals, captcha_policy, pow_difficulty
+                 FROM boards
             "#,
         )
         .fetch_all(&self.pool)
@@ -222,49 +741,396 @@ impl PoolModel {
         .expect("Oops")
     }
 
+    /// Matches either the exact content hash or a perceptual hash within `PHASH_DISTANCE` bits.
+    pub async fn is_banned(&self, hash: &str, phash: Option<i64>) -> Result<bool> {
+        let banned = sqlx::query_scalar!(
+            r#"
+                SELECT EXISTS (
+                    SELECT 1 FROM banned_files
+                    WHERE hash = $1
+                    OR length(replace(((phash # $2)::bit(64))::text, '0', '')) <= $3
+                ) AS "banned!"
+                "#,
+            hash,
+            phash,
+            PHASH_DISTANCE,
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(banned)
+    }
+
+    /// Bans every file attached to the post, returns how many new entries were added.
+    pub async fn ban_post_files(&self, id: i32, reason: &str, banned_by: i32) -> Result<u64> {
+        let reason = Some(reason.trim()).filter(|x| !x.is_empty());
+        let result = sqlx::query!(
+            r#"
+                INSERT INTO banned_files(hash, phash, reason, banned_by)
+                SELECT hash, phash, $2, $3 FROM files
+                WHERE hash IN (SELECT file FROM post_files WHERE post = $1)
+                ON CONFLICT (hash) DO NOTHING
+                "#,
+            id,
+            reason,
+            banned_by,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        info!(
+            "{} files of post {} banned by user {}",
+            result.rows_affected(),
+            id,
+            banned_by
+        );
+        Ok(result.rows_affected())
+    }
+
+    pub async fn unban_file(&self, hash: &str) -> Result<()> {
+        sqlx::query!(
+            r#"
+                DELETE FROM banned_files WHERE hash = $1
+                "#,
+            hash,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn banned_files(&self) -> Result<Vec<BannedFile>> {
+        Ok(sqlx::query_as!(
+            BannedFile,
+            r#"
+                SELECT hash, reason, created FROM banned_files ORDER BY created DESC
+                "#,
+        )
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    /// Answers to a challenge with `ignore_case` are lowercased before comparing them, so its
+    /// `solution` has to be lowercase already.
+    pub async fn create_captcha(
+        &self,
+        id: &str,
+        solution: &str,
+        ignore_case: bool,
+        lifetime: f64,
+    ) -> Result<()> {
+        sqlx::query!(
+            r#"
+                INSERT INTO captchas(id, solution, ignore_case, expires)
+                VALUES ($1, $2, $3, now() + make_interval(secs => $4))
+                "#,
+            id,
+            solution,
+            ignore_case,
+            lifetime,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// A challenge can only be answered once, wrong answers use it up as well.
+    pub async fn consume_captcha(&self, id: &str, answer: &str) -> Result<bool> {
+        let captcha = sqlx::query!(
+            r#"
+                DELETE FROM captchas WHERE id = $1
+                RETURNING solution, ignore_case, expires > now() AS "valid!"
+                "#,
+            id,
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(match captcha {
+            Some(c) if c.valid && c.ignore_case => c.solution == answer.trim().to_lowercase(),
+            Some(c) => c.valid && c.solution == answer.trim(),
+            None => false,
+        })
+    }
+
+    /// Removes expired captchas and proof of work challenges.
+    pub async fn expire_captchas(&self) -> Result<u64> {
+        let captchas = sqlx::query!(
+            r#"
+                DELETE FROM captchas WHERE expires <= now()
+                "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        let challenges = sqlx::query!(
+            r#"
+                DELETE FROM pow_challenges WHERE expires <= now()
+                "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(captchas.rows_affected() + challenges.rows_affected())
+    }
+
+    pub async fn create_pow(&self, nonce: &str, lifetime: f64) -> Result<()> {
+        sqlx::query!(
+            r#"
+                INSERT INTO pow_challenges(nonce, expires)
+                VALUES ($1, now() + make_interval(secs => $2))
+                "#,
+            nonce,
+            lifetime,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Like captchas a nonce can only be used once, whether the work is sufficient or not.
+    pub async fn consume_pow(&self, nonce: &str, counter: &str, difficulty: u32) -> Result<bool> {
+        let challenge = sqlx::query_scalar!(
+            r#"
+                DELETE FROM pow_challenges WHERE nonce = $1
+                RETURNING expires > now() AS "valid!"
+                "#,
+            nonce,
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(challenge == Some(true) && verify_pow(nonce, counter, difficulty))
+    }
+
+    /// Whether a post needs a solved challenge under `policy`, staff are never asked.
+    pub async fn captcha_required(
+        &self,
+        policy: CaptchaPolicy,
+        role: Option<&Role>,
+        parent: Option<i32>,
+        ip: IpAddr,
+        window: u64,
+    ) -> Result<bool> {
+        match policy {
+            _ if role.is_some_and(Role::is_staff) => Ok(false),
+            CaptchaPolicy::Always => Ok(true),
+            CaptchaPolicy::Threads => Ok(parent.is_none()),
+            CaptchaPolicy::FirstPost => Ok(!self.has_posted(ip, window).await?),
+            CaptchaPolicy::Never => Ok(false),
+        }
+    }
+
+    /// Clears the address of every post older than `retention` seconds.
+    pub async fn forget_addresses(&self, retention: f64) -> Result<u64> {
+        let result = sqlx::query!(
+            r#"
+                UPDATE posts SET ip = NULL
+                WHERE ip IS NOT NULL AND created < now() - make_interval(secs => $1)
+                "#,
+            retention,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Whether `ip` created a post in the last `window` seconds.
+    pub async fn has_posted(&self, ip: IpAddr, window: u64) -> Result<bool> {
+        let posted = sqlx::query_scalar!(
+            r#"
+                SELECT EXISTS(
+                    SELECT 1 FROM posts
+                    WHERE ip = $1 AND created > now() - make_interval(secs => $2)
+                ) AS "posted!"
+                "#,
+            ip.to_string(),
+            window as f64,
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(posted)
+    }
+
+    /// Reads the form and checks the size and type of every file against the board's policy,
+    /// the files are spooled to disk but not looked into any further.
     pub async fn parse_fields(
         &self,
         mut multipart: multer::Multipart<'_>,
-    ) -> Result<Input, Report> {
+        board: &Board,
+        policy: &UploadPolicy<'_>,
+    ) -> Result<(Input, Vec<ReceivedFile>), Report> {
         let mut result: Input = Default::default();
-        let mut files: Vec<_> = Vec::new();
+        let mut received: Vec<ReceivedFile> = Vec::new();
+        // `spoilerN` checkboxes belong to the `fileN` inputs, empty file inputs are skipped
+        let mut spoiler_keys: Vec<_> = Vec::new();
+        let mut checked = HashSet::new();
 
-        while let Some(field) = multipart.next_field().await? {
+        while let Some(mut field) = multipart.next_field().await? {
             let key = field.name().ok_or(RequestError::MissingKey)?.to_owned();
-            let value: Vec<u8> = field.bytes().await?.to_vec();
 
-            match value.len() {
-                0 => {}
-                _ => match value.clone().sniff_mime_type() {
-                    Some(mime) => {
-                        let id = &value[0..32];
-                        let name = format_name((id, mime.split('/').last().unwrap().to_owned()));
-                        files.push(name.clone());
+            // browsers send a filename, even an empty one, for every file input
+            if field.file_name().is_none() && field.content_type().is_none() {
+                let value = read_text(&mut field, &key).await?;
 
-                        task::spawn(async move { save_file(name, &value).await });
-                    }
-                    None => {
-                        // edge-case since bevy_reflect forces the user to downcast T
-                        match key.as_str() {
-                            "parent" => {
-                                // 49 == ASCII for 1, stupid because if value[0]::<u8> >= u8::MAX
-                                // this will result in undefined behavior
-                                result.parent = Some((value[0] - 48) as i32)
-                            }
-                            _ => {
-                                *result.get_field_mut::<String>(&key).unwrap() =
-                                    std::str::from_utf8(&value).unwrap().to_owned();
-                            }
-                        }
+                // edge-case since bevy_reflect forces the user to downcast T
+                match key.as_str() {
+                    "parent" if value.trim().is_empty() => {}
+                    "parent" => match value.trim().parse() {
+                        Ok(parent) => result.parent = Some(parent),
+                        Err(_) => return Err(RequestError::InvalidParent(value).into()),
+                    },
+                    k if k.starts_with("spoiler") => {
+                        checked.insert(key.clone());
                     }
-                },
+                    _ => match result.get_field_mut::<String>(&key) {
+                        Some(slot) => *slot = value,
+                        None => return Err(RequestError::UnknownField(key).into()),
+                    },
+                }
+                continue;
+            }
+
+            if !key.starts_with("file") {
+                return Err(RequestError::UnknownField(key).into());
+            }
+            let filename = field.file_name().and_then(clean_filename);
+            let declared = field.content_type().map(ToString::to_string);
+            let Upload {
+                file,
+                size,
+                hasher,
+                head,
+            } = receive_file(&mut field, policy.max_file_size).await?;
+
+            // an empty file input
+            if size == 0 {
+                continue;
+            }
+            if policy.text_only {
+                return Err(RequestError::TextOnly.into());
+            }
+            if received.len() >= policy.max_files {
+                return Err(RequestError::TooManyFiles.into());
+            }
+
+            // the declared type is only used for error messages, the contents decide
+            let mime = match sniff_mime(&head) {
+                Some(mime) => mime.to_owned(),
+                None => {
+                    let declared = declared.unwrap_or_else(|| "unknown".to_owned());
+                    return Err(RequestError::UnsupportedType(declared).into());
+                }
+            };
+            if !policy.allowed_mimes.contains(&mime) {
+                return Err(RequestError::UnsupportedType(mime).into());
             }
+
+            spoiler_keys.push(key.replacen("file", "spoiler", 1));
+            received.push(ReceivedFile {
+                file,
+                size,
+                hasher,
+                mime,
+                filename,
+                spoiler: false,
+            });
+        }
+
+        for (file, key) in received.iter_mut().zip(&spoiler_keys) {
+            file.spoiler = checked.contains(key);
+        }
+        result.board = board.name.clone();
+        if result.op.trim().is_empty() {
+            result.op = board.default_name().to_owned();
         }
 
         info!("created post: {:?}", result);
+        Ok((result, received))
+    }
+
+    /// Strips, hashes, measures and probes received files, which is only worth doing for
+    /// posts whose challenge passed.
+    pub async fn stage_files(
+        &self,
+        files: Vec<ReceivedFile>,
+        policy: &UploadPolicy<'_>,
+    ) -> Result<Vec<StagedFile>> {
+        let mut staged: Vec<_> = Vec::new();
+
+        for ReceivedFile {
+            file: spooled,
+            size: received,
+            hasher,
+            mime,
+            filename,
+            spoiler,
+        } in files
+        {
+            let (hash, size, image) = match mime.starts_with("image/") {
+                true => {
+                    let bytes = tokio::fs::read(spooled.path()).await?;
+
+                    // never store location or camera details from users' photos
+                    let bytes = match strip_metadata(&bytes, &mime) {
+                        Some(stripped) => {
+                            tokio::fs::write(spooled.path(), &stripped).await?;
+                            stripped
+                        }
+                        None if policy.keep_originals => bytes,
+                        None => return Err(RequestError::Metadata(mime).into()),
+                    };
+                    (content_hash(&bytes), bytes.len(), Some(bytes))
+                }
+                false => (encode_hash(hasher), received, None),
+            };
 
-        result.files = Some(files);
-        Ok(result)
+            let name = format!("{}.{}", hash, file_extension(&mime));
+            let thumbnail = thumbnail_name(&name, &mime);
+            let media = match mime.starts_with("video/") || mime.starts_with("audio/") {
+                true => probe_media(spooled.path())
+                    .await
+                    .unwrap_or_else(|e| {
+                        warn!("failed to probe {}: {}", name, e);
+                        MediaInfo::default()
+                    }),
+                false => MediaInfo::default(),
+            };
+            let dimensions = image
+                .as_deref()
+                .and_then(image_dimensions)
+                .or_else(|| media.width.zip(media.height));
+            let phash = image.as_deref().and_then(perceptual_hash);
+
+            if self.is_banned(&hash, phash).await? {
+                return Err(RequestError::BannedFile.into());
+            }
+
+            let info = FileInfo {
+                hash,
+                name,
+                mime,
+                size: size as i64,
+                width: dimensions.map(|(w, _)| w as i32),
+                height: dimensions.map(|(_, h)| h as i32),
+                filename,
+                thumbnail,
+                phash,
+                duration: media.duration,
+            };
+            staged.push(StagedFile {
+                info,
+                file: Arc::new(spooled),
+                spoiler,
+            });
+        }
+
+        Ok(staged)
     }
 
     // pub async fn merge_thread(&self, old: u32, new: u32) -> Result<()> {
@@ -279,3 +1145,483 @@ impl PoolModel {
     //     Ok(())
     // }
 }
+
+#[sqlx::test]
+async fn test_reconcile_boards(pool: PgPool) -> Result<(), Report> {
+    let models = PoolModel { pool };
+    let boards = vec![
+        ("g".to_owned(), "technology".to_owned()),
+        ("b".to_owned(), "random".to_owned()),
+    ];
+
+    models.reconcile_boards(&boards, false).await?;
+    models.reconcile_boards(&boards, false).await?;
+    assert_eq!(models.get_boards().await.len(), 2);
+
+    models.reconcile_boards(&boards[..1], true).await?;
+    let names: Vec<_> = models.get_boards().await.into_iter().map(|x| x.name).collect();
+    assert_eq!(names, vec!["g".to_owned()]);
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn test_signup_login(pool: PgPool) -> Result<(), Report> {
+    let models = PoolModel { pool };
+    let credentials = |username: &str, password: &str, role| Credentials {
+        username: username.to_owned(),
+        password: password.to_owned(),
+        role,
+    };
+
+    assert!(!models.has_users().await?);
+    models.signup(credentials("nino", "hunter2", Some(Role::Moderator))).await?;
+    models.signup(credentials("miku", "hunter3", None)).await?;
+    assert!(models.has_users().await?);
+
+    let (_, role) = models.login(credentials("nino", "hunter2", None)).await?;
+    assert_eq!(role, Role::Moderator);
+    let (_, role) = models.login(credentials("miku", "hunter3", None)).await?;
+    assert_eq!(role, Role::User);
+
+    let err = models.login(credentials("nino", "hunter3", None)).await.unwrap_err();
+    assert!(matches!(err, LoginError::InvalidCredentials));
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn test_parse_fields(pool: PgPool) -> Result<(), Report> {
+    let models = PoolModel { pool };
+    let board = Board {
+        name: "b".to_owned(),
+        title: "random".to_owned(),
+        threads_per_page: None,
+        max_pages: None,
+        max_file_size: None,
+        allowed_mimes: None,
+        max_files: None,
+        default_name: None,
+        text_only: false,
+        nsfw: false,
+        keep_originals: false,
+        captcha_policy: None,
+        pow_difficulty: None,
+    };
+    let allowed = vec!["image/png".to_owned()];
+    let policy = UploadPolicy {
+        max_file_size: 1024,
+        max_files: 3,
+        allowed_mimes: &allowed,
+        text_only: false,
+        keep_originals: false,
+    };
+
+    let text = |name: &str, value: &str| {
+        format!(
+            "--X\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+            name, value
+        )
+        .into_bytes()
+    };
+    let file = |name: &str, value: &[u8]| {
+        let header = format!(
+            "--X\r\nContent-Disposition: form-data; name=\"{}\"; filename=\"a.png\"\r\n\
+             Content-Type: image/png\r\n\r\n",
+            name
+        );
+        [header.as_bytes(), value, &b"\r\n"[..]].concat()
+    };
+    let form = |fields: Vec<Vec<u8>>| {
+        let body = [fields.concat(), b"--X--\r\n".to_vec()].concat();
+        multer::Multipart::new(
+            futures::stream::once(async move { Ok::<_, std::io::Error>(body) }),
+            "X",
+        )
+    };
+    let (models, board, policy) = (&models, &board, &policy);
+    let parse = move |fields| models.parse_fields(form(fields), board, policy);
+
+    // text that looks like a known format stays text
+    let (input, received) = parse(vec![text("body", "GIF89a hello"), file("file1", b"")]).await?;
+    assert_eq!(input.body, "GIF89a hello");
+    assert!(received.is_empty());
+
+    let (input, _) = parse(vec![text("parent", "12")]).await?;
+    assert_eq!(input.parent, Some(12));
+
+    let err = RequestError::from(parse(vec![text("parent", "1a")]).await.unwrap_err());
+    assert!(matches!(err, RequestError::InvalidParent(x) if x == "1a"));
+
+    let err = RequestError::from(parse(vec![text("foo", "bar")]).await.unwrap_err());
+    assert!(matches!(err, RequestError::UnknownField(x) if x == "foo"));
+
+    let err = RequestError::from(parse(vec![file("file1", &[b'a'; 2000])]).await.unwrap_err());
+    assert!(matches!(err, RequestError::SizeLimit));
+
+    // the declared type isn't trusted
+    let err = RequestError::from(parse(vec![file("file1", b"plain text")]).await.unwrap_err());
+    assert!(matches!(err, RequestError::UnsupportedType(x) if x == "image/png"));
+
+    // accepted files are only staged until the post is created
+    let mut png = std::io::Cursor::new(Vec::new());
+    image::DynamicImage::new_rgb8(2, 2).write_to(&mut png, image::ImageOutputFormat::Png)?;
+    let fields = vec![file("file1", png.get_ref()), text("spoiler1", "true")];
+    let (_, received) = parse(fields).await?;
+    let staged = models.stage_files(received, policy).await?;
+    assert_eq!(staged.len(), 1);
+    assert!(staged[0].info.name.ends_with(".png"));
+    assert_eq!(staged[0].info.width, Some(2));
+    assert!(staged[0].spoiler);
+    assert!(staged[0].file.path().exists());
+    let stored = sqlx::query_scalar!(r#"SELECT count(*) AS "count!" FROM files"#)
+        .fetch_one(&models.pool)
+        .await?;
+    assert_eq!(stored, 0);
+
+    Ok(())
+}
+
+/// Board `b` with local storage in a directory of its own, which is removed again once the
+/// test is done with it.
+#[cfg(test)]
+struct Fixture {
+    models: PoolModel,
+    storage: Arc<dyn Storage>,
+    root: std::path::PathBuf,
+}
+
+#[cfg(test)]
+impl Fixture {
+    const IP: IpAddr = IpAddr::V4(std::net::Ipv4Addr::LOCALHOST);
+
+    async fn new(pool: PgPool, name: &str) -> Result<Fixture> {
+        let models = PoolModel { pool };
+        models
+            .reconcile_boards(&[("b".to_owned(), "random".to_owned())], false)
+            .await?;
+        let root = crate::utils::helpers::test_dir(name);
+        let storage = Arc::new(crate::utils::storage::LocalStorage::new(&root)?);
+
+        Ok(Fixture {
+            models,
+            storage,
+            root,
+        })
+    }
+
+    /// A new thread on `/b/`, or a reply when `parent` is set.
+    fn post(parent: Option<i32>) -> Input {
+        Input {
+            board: "b".to_owned(),
+            op: "Anonymous".to_owned(),
+            body: "hello".to_owned(),
+            parent,
+            ..Default::default()
+        }
+    }
+
+    async fn last_id(&self) -> Result<i32> {
+        let id = sqlx::query_scalar!("SELECT max(id) FROM posts")
+            .fetch_one(&self.models.pool)
+            .await?;
+        id.ok_or_else(|| eyre!("no posts yet"))
+    }
+}
+
+#[cfg(test)]
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.root);
+    }
+}
+
+#[sqlx::test]
+async fn test_create_post_parent(pool: PgPool) -> Result<(), Report> {
+    let fixture = Fixture::new(pool, "create-post-parent").await?;
+    let (models, storage) = (&fixture.models, &fixture.storage);
+    models
+        .reconcile_boards(&[("g".to_owned(), "technology".to_owned())], false)
+        .await?;
+
+    let post = |board: &str, parent| Input {
+        board: board.to_owned(),
+        ..Fixture::post(parent)
+    };
+    let parent_error = |e: Report| match RequestError::from(e) {
+        RequestError::NonExistentThread(id) => id,
+        e => panic!("unexpected error {}", e),
+    };
+    let create = move |input: Input| async move {
+        models
+            .create_post(&input, &[], storage, Fixture::IP, 300, 150)
+            .await
+    };
+
+    create(post("b", None)).await?;
+    let thread = fixture.last_id().await?;
+    create(post("b", Some(thread))).await?;
+    let reply = fixture.last_id().await?;
+
+    let err = create(post("b", Some(reply))).await.unwrap_err();
+    assert_eq!(parent_error(err), reply);
+    let err = create(post("g", Some(thread))).await.unwrap_err();
+    assert_eq!(parent_error(err), thread);
+    let err = create(post("b", Some(thread + 100))).await.unwrap_err();
+    assert_eq!(parent_error(err), thread + 100);
+
+    models.delete_thread(thread, 1).await?;
+    let err = create(post("b", Some(thread))).await.unwrap_err();
+    assert_eq!(parent_error(err), thread);
+
+    assert!(models.has_posted(Fixture::IP, 60).await?);
+    assert!(!models.has_posted(std::net::Ipv4Addr::new(10, 0, 0, 1).into(), 60).await?);
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn test_captcha_required(pool: PgPool) -> Result<(), Report> {
+    use CaptchaPolicy::*;
+
+    let fixture = Fixture::new(pool, "captcha-required").await?;
+    let models = &fixture.models;
+    let required = move |policy, role: Option<Role>, parent| async move {
+        models
+            .captcha_required(policy, role.as_ref(), parent, Fixture::IP, 60)
+            .await
+    };
+
+    // signing in isn't enough, only staff roles skip the challenge
+    assert!(required(Always, None, None).await?);
+    assert!(required(Always, Some(Role::User), None).await?);
+    assert!(!required(Always, Some(Role::Volunteer), None).await?);
+    assert!(!required(Always, Some(Role::Admin), None).await?);
+    assert!(required(Threads, None, None).await?);
+    assert!(!required(Threads, None, Some(1)).await?);
+    assert!(!required(Never, None, None).await?);
+
+    assert!(required(FirstPost, None, Some(1)).await?);
+    models
+        .create_post(&Fixture::post(None), &[], &fixture.storage, Fixture::IP, 300, 150)
+        .await?;
+    assert!(!required(FirstPost, None, Some(1)).await?);
+
+    // once the address is forgotten the poster counts as new again
+    sqlx::query!("UPDATE posts SET created = now() - interval '2 hours'")
+        .execute(&models.pool)
+        .await?;
+    assert_eq!(models.forget_addresses(3600.0).await?, 1);
+    assert_eq!(models.forget_addresses(3600.0).await?, 0);
+    sqlx::query!("UPDATE posts SET created = now()")
+        .execute(&models.pool)
+        .await?;
+    assert!(required(FirstPost, None, Some(1)).await?);
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn test_bump_limit(pool: PgPool) -> Result<(), Report> {
+    let fixture = Fixture::new(pool, "bump-limit").await?;
+    let (models, pool, storage) = (&fixture.models, &fixture.models.pool, &fixture.storage);
+    let create = move |input: Input| async move {
+        models
+            .create_post(&input, &[], storage, Fixture::IP, 2, 150)
+            .await
+    };
+    // moves the last bump an hour back and tells whether a reply brought it forward again
+    let bumps = move |id: i32| async move {
+        sqlx::query!("UPDATE posts SET bumped = now() - interval '1 hour' WHERE id = $1", id)
+            .execute(pool)
+            .await?;
+        create(Fixture::post(Some(id))).await?;
+        let bumped = sqlx::query_scalar!(
+            r#"SELECT bumped > now() - interval '1 minute' AS "bumped!" FROM posts WHERE id = $1"#,
+            id
+        )
+        .fetch_one(pool)
+        .await?;
+        Ok::<_, Report>(bumped)
+    };
+
+    create(Fixture::post(None)).await?;
+    let thread = fixture.last_id().await?;
+    assert!(bumps(thread).await?);
+    assert!(bumps(thread).await?);
+    let reply = fixture.last_id().await?;
+    // the third reply is past the limit
+    assert!(!bumps(thread).await?);
+
+    // deleted replies don't count towards it
+    models.delete_thread(reply, 1).await?;
+    models.delete_thread(fixture.last_id().await?, 1).await?;
+    assert!(bumps(thread).await?);
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn test_archive_overflow(pool: PgPool) -> Result<(), Report> {
+    let fixture = Fixture::new(pool, "archive-overflow").await?;
+    let models = &fixture.models;
+    for _ in 0..4 {
+        models
+            .create_post(&Fixture::post(None), &[], &fixture.storage, Fixture::IP, 300, 2)
+            .await?;
+    }
+
+    // the oldest threads fell off without waiting for the background task
+    assert_eq!(models.count_threads("b", false).await, 2);
+    assert_eq!(models.count_archived("b", false).await, 2);
+
+    // the archive is paged with the most recently archived thread first
+    let thread = fixture.last_id().await? - 2;
+    let page = |n| models.get_archive("b".to_owned(), n, 1, false);
+    assert_eq!(page(0).await.iter().map(|x| x.id).collect::<Vec<_>>(), vec![thread]);
+    assert_eq!(page(1).await.iter().map(|x| x.id).collect::<Vec<_>>(), vec![thread - 1]);
+    assert!(page(2).await.is_empty());
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn test_create_post_files(pool: PgPool) -> Result<(), Report> {
+    let fixture = Fixture::new(pool, "create-post-files").await?;
+    let (models, storage) = (&fixture.models, &fixture.storage);
+    // stored names are content hashes, the table doesn't take anything shorter
+    let (pdf, png) = (format!("{}.pdf", "a".repeat(64)), format!("{}.png", "b".repeat(64)));
+    let thumbnail = format!("t_{}", png);
+
+    let (file, mut out) = TempFile::create().await?;
+    out.write_all(b"%PDF-1.4").await?;
+    out.flush().await?;
+    let staged = StagedFile {
+        info: FileInfo {
+            name: pdf.clone(),
+            hash: "a".repeat(64),
+            mime: "application/pdf".to_owned(),
+            size: 8,
+            width: None,
+            height: None,
+            filename: Some("a.pdf".to_owned()),
+            thumbnail: None,
+            phash: None,
+            duration: None,
+        },
+        file: Arc::new(file),
+        spoiler: true,
+    };
+    let thread = Fixture::post(None);
+    let pool = &models.pool;
+    let counts = move || async move {
+        sqlx::query!(
+            r#"
+                SELECT (SELECT count(*) FROM files) AS "files!",
+                (SELECT count(*) FROM post_files) AS "links!"
+                "#
+        )
+        .fetch_one(pool)
+        .await
+        .map(|r| (r.files, r.links))
+    };
+
+    models
+        .create_post(&thread, &[staged.clone()], storage, Fixture::IP, 300, 150)
+        .await?;
+    assert!(storage.exists(&pdf).await?);
+    assert_eq!(counts().await?, (1, 1));
+    let post = models.get_post(fixture.last_id().await?, false).await.unwrap();
+    let attachments = post.attachments();
+    assert_eq!(attachments.len(), 1);
+    assert_eq!(attachments[0].name, pdf);
+    assert_eq!(attachments[0].filename.as_deref(), Some("a.pdf"));
+    assert_eq!(attachments[0].size, Some(8));
+    assert!(attachments[0].spoiler);
+
+    // a known file whose object went missing is put again instead of trusting the row
+    storage.delete(&pdf).await?;
+    models
+        .create_post(&thread, &[staged.clone()], storage, Fixture::IP, 300, 150)
+        .await?;
+    assert!(storage.exists(&pdf).await?);
+    assert_eq!(counts().await?, (1, 2));
+
+    // an image that can't be decoded is posted without the thumbnail it was promised
+    let (file, mut out) = TempFile::create().await?;
+    out.write_all(b"\x89PNG\r\n\x1a\n").await?;
+    out.flush().await?;
+    let staged = StagedFile {
+        info: FileInfo {
+            name: png.clone(),
+            hash: "b".repeat(64),
+            mime: "image/png".to_owned(),
+            size: 8,
+            thumbnail: Some(thumbnail.clone()),
+            ..staged.info
+        },
+        file: Arc::new(file),
+        spoiler: false,
+    };
+    models
+        .create_post(&thread, &[staged], storage, Fixture::IP, 300, 150)
+        .await?;
+    assert!(!storage.exists(&thumbnail).await?);
+    let post = models.get_post(fixture.last_id().await?, false).await.unwrap();
+    assert_eq!(post.attachments()[0].name, png);
+    assert_eq!(post.attachments()[0].thumbnail, None);
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn test_consume_captcha(pool: PgPool) -> Result<(), Report> {
+    let models = PoolModel { pool };
+    let id = |n: char| n.to_string().repeat(40);
+
+    models.create_captcha(&id('a'), "x7Kp", false, 600.0).await?;
+    assert!(models.consume_captcha(&id('a'), "x7Kp").await?);
+    // replaying a solved challenge
+    assert!(!models.consume_captcha(&id('a'), "x7Kp").await?);
+
+    // a wrong answer burns the challenge
+    models.create_captcha(&id('b'), "x7Kp", false, 600.0).await?;
+    assert!(!models.consume_captcha(&id('b'), "1234").await?);
+    assert!(!models.consume_captcha(&id('b'), "x7Kp").await?);
+
+    models.create_captcha(&id('c'), "x7Kp", false, -1.0).await?;
+    models.create_captcha(&id('d'), "x7Kp", false, -1.0).await?;
+    assert!(!models.consume_captcha(&id('c'), "x7Kp").await?);
+    assert_eq!(models.expire_captchas().await?, 1);
+
+    // answers to questions don't depend on case, image captchas do
+    models.create_captcha(&id('e'), "x7kp", false, 600.0).await?;
+    assert!(!models.consume_captcha(&id('e'), "X7KP").await?);
+    models.create_captcha(&id('f'), "tokichan", true, 600.0).await?;
+    assert!(models.consume_captcha(&id('f'), " Tokichan").await?);
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn test_consume_pow(pool: PgPool) -> Result<()> {
+    let models = PoolModel { pool };
+    let nonce = "ab".repeat(20);
+    let counter = (0u32..)
+        .map(|n| n.to_string())
+        .find(|n| verify_pow(&nonce, n, 8))
+        .unwrap();
+
+    models.create_pow(&nonce, 600.0).await?;
+    assert!(models.consume_pow(&nonce, &counter, 8).await?);
+    // nonces can't be reused
+    assert!(!models.consume_pow(&nonce, &counter, 8).await?);
+
+    models.create_pow(&nonce, 600.0).await?;
+    assert!(!models.consume_pow(&nonce, &counter, 32).await?);
+
+    models.create_pow(&nonce, -1.0).await?;
+    assert!(!models.consume_pow(&nonce, &counter, 8).await?);
+
+    Ok(())
+}
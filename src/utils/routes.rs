@@ -3,7 +3,7 @@ use std::{sync::Arc, time::Duration};
 use super::{
     captcha::CaptchaService,
     handlers,
-    middleware::{captcha_cookie, parse_fields, signed_in},
+    middleware::{captcha_cookie, parse_fields, question_cookie, signed_in},
 };
 use crate::App;
 
@@ -12,14 +12,13 @@ use axum::{
     error_handling::HandleErrorLayer,
     extract::DefaultBodyLimit,
     middleware,
-    routing::{get, post},
+    routing::{get, get_service, post},
     Extension, Router,
 };
-use axum_extra::routing::SpaRouter;
 use axum_sessions::{async_session::MemoryStore, SessionLayer};
 use tokio::sync::RwLock;
 use tower::{Layer, ServiceBuilder};
-use tower_http::trace::TraceLayer;
+use tower_http::{services::ServeDir, trace::TraceLayer};
 
 pub fn routes(app: Arc<App>, cs: Arc<RwLock<CaptchaService>>) -> Router {
     let store = MemoryStore::new();
@@ -29,6 +28,10 @@ pub fn routes(app: Arc<App>, cs: Arc<RwLock<CaptchaService>>) -> Router {
     let timeout_layer = ServiceBuilder::new()
         .layer(HandleErrorLayer::new(handlers::timeout))
         .timeout(Duration::from_millis(5000));
+    // sending a few files over a slow connection takes a while
+    let upload_timeout_layer = ServiceBuilder::new()
+        .layer(HandleErrorLayer::new(handlers::timeout))
+        .timeout(Duration::from_secs(300));
 
     let parse_fields =
         ServiceBuilder::new().layer(middleware::from_fn_with_state(app.clone(), parse_fields));
@@ -39,25 +42,59 @@ pub fn routes(app: Arc<App>, cs: Arc<RwLock<CaptchaService>>) -> Router {
         .route("/logout", get(handlers::logout))
         .route("/mod", get(handlers::get_mod))
         .route("/recent", get(handlers::get_recent))
+        .route("/delete", post(handlers::delete_thread))
+        .route("/restore", post(handlers::restore_thread))
+        .route("/edit", post(handlers::edit_thread))
+        .route("/ban", post(handlers::ban_file))
+        .route("/unban", post(handlers::unban_file))
+        .route("/board/create", post(handlers::create_board))
+        .route("/board/rename", post(handlers::rename_board))
+        .route("/board/delete", post(handlers::delete_board))
         .route(
             "/captcha",
             get(handlers::captcha)
-                .layer(middleware::from_fn(captcha_cookie))
-                .route_layer(Extension(cs)),
+                .layer(middleware::from_fn_with_state(app.clone(), captcha_cookie))
+                .route_layer(Extension(cs.clone())),
+        )
+        // a text question for those who can't solve the image, e.g. with a screen reader
+        .route(
+            "/captcha/audio",
+            get(handlers::captcha_question)
+                .layer(middleware::from_fn_with_state(app.clone(), question_cookie)),
+        )
+        .route("/pow/:board", get(handlers::pow_challenge))
+        .route(
+            "/captcha/stats",
+            get(handlers::captcha_stats).route_layer(Extension(cs)),
         );
 
+    // uploads are bounded by the `parse_fields` middleware using the configured limits
+    let create_post = post(handlers::create_post).layer(
+        ServiceBuilder::new()
+            .layer(upload_timeout_layer)
+            .layer(DefaultBodyLimit::disable())
+            .layer(parse_fields),
+    );
+
     Router::new()
-        .layer(DefaultBodyLimit::disable())
-        .merge(SpaRouter::new("/tmp", ".tmp"))
+        .route("/tmp/:name", get(handlers::get_file))
+        .nest_service(
+            "/static",
+            get_service(ServeDir::new("ui/static")).handle_error(handlers::static_error),
+        )
         .route("/", get(handlers::get_root))
         .route("/:board/", get(handlers::get_board))
-        .route("/:board/", post(handlers::create_post).layer(parse_fields))
+        .route("/:board/page/:page", get(handlers::get_board_page))
+        .route("/:board/archive", get(handlers::get_archive))
+        .route("/:board/archive/page/:page", get(handlers::get_archive_page))
         .route("/:board/:id", get(handlers::get_post))
         .nest("/.toki", hidden)
+        // only covers the routes above, uploads come with a timeout of their own
+        .route_layer(timeout_layer)
+        .route("/:board/", create_post)
         .route_layer(middleware::from_fn(signed_in))
         .with_state(app)
         .layer(DefaultBodyLimit::max(1024))
         .layer(session_layer)
-        .layer(timeout_layer.into_inner())
         .layer(TraceLayer::new_for_http())
 }
@@ -0,0 +1,533 @@
+use std::{
+    io::{self, ErrorKind, SeekFrom},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use axum::body::Bytes;
+use chrono::Utc;
+use color_eyre::{eyre::eyre, Report};
+use futures::stream::{self, BoxStream, StreamExt};
+use hmac::{Hmac, Mac};
+use regex::Regex;
+use reqwest::{header, Method, StatusCode, Url};
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs,
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt},
+};
+use tracing::info;
+
+use super::config::{Config, S3Config, StorageConfig};
+
+/// Backend that holds uploaded files and their thumbnails, files are addressed by name only.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn put(&self, name: &str, bytes: &[u8]) -> Result<(), Report>;
+    /// Stores the contents of a local file, backends that can should avoid reading it whole.
+    async fn put_file(&self, name: &str, path: &Path) -> Result<(), Report> {
+        let bytes = fs::read(path).await?;
+        self.put(name, &bytes).await
+    }
+    async fn get(&self, name: &str) -> Result<Option<Vec<u8>>, Report>;
+    async fn exists(&self, name: &str) -> Result<bool, Report> {
+        Ok(self.get(name).await?.is_some())
+    }
+    async fn delete(&self, name: &str) -> Result<(), Report>;
+    async fn list(&self) -> Result<Vec<String>, Report>;
+    /// Size of a file in bytes, `None` when it doesn't exist.
+    async fn size(&self, name: &str) -> Result<Option<u64>, Report>;
+    /// Reads the bytes in `range` of a file piece by piece, so serving it doesn't mean holding
+    /// all of it in memory.
+    async fn stream(&self, name: &str, range: Range<u64>) -> Result<Option<ByteStream>, Report>;
+}
+
+pub type ByteStream = BoxStream<'static, io::Result<Bytes>>;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+fn read_chunks(reader: impl AsyncRead + Send + Unpin + 'static) -> ByteStream {
+    stream::try_unfold(reader, |mut reader| async move {
+        let mut chunk = vec![0; CHUNK_SIZE];
+        match reader.read(&mut chunk).await? {
+            0 => Ok(None),
+            n => {
+                chunk.truncate(n);
+                Ok(Some((Bytes::from(chunk), reader)))
+            }
+        }
+    })
+    .boxed()
+}
+
+pub fn open_storage(config: &Config) -> Result<Arc<dyn Storage>, Report> {
+    match &config.storage {
+        StorageConfig::Local => Ok(Arc::new(LocalStorage::new(&config.main.file_directory)?)),
+        StorageConfig::S3(s3) => Ok(Arc::new(S3Storage::new(s3)?)),
+    }
+}
+
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: impl AsRef<Path>) -> Result<Self, Report> {
+        std::fs::create_dir_all(root.as_ref())?;
+
+        Ok(Self {
+            root: root.as_ref().to_owned(),
+        })
+    }
+
+    fn path(&self, name: &str) -> Result<PathBuf, Report> {
+        // names are generated by us, but never allow escaping the root
+        if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+            return Err(eyre!("invalid file name {}", name));
+        }
+        Ok(self.root.join(name))
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn put(&self, name: &str, bytes: &[u8]) -> Result<(), Report> {
+        info!("saving {} with length {} ...", name, bytes.len());
+
+        fs::write(self.path(name)?, bytes).await?;
+        Ok(())
+    }
+
+    async fn put_file(&self, name: &str, path: &Path) -> Result<(), Report> {
+        info!("saving {} from {} ...", name, path.display());
+
+        fs::copy(path, self.path(name)?).await?;
+        Ok(())
+    }
+
+    async fn get(&self, name: &str) -> Result<Option<Vec<u8>>, Report> {
+        match fs::read(self.path(name)?).await {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn exists(&self, name: &str) -> Result<bool, Report> {
+        match fs::metadata(self.path(name)?).await {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn delete(&self, name: &str) -> Result<(), Report> {
+        match fs::remove_file(self.path(name)?).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn list(&self) -> Result<Vec<String>, Report> {
+        let mut names = Vec::new();
+        let mut dir = fs::read_dir(&self.root).await?;
+
+        while let Some(entry) = dir.next_entry().await? {
+            if let Some(name) = entry.file_name().to_str() {
+                names.push(name.to_owned());
+            }
+        }
+        Ok(names)
+    }
+
+    async fn size(&self, name: &str) -> Result<Option<u64>, Report> {
+        match fs::metadata(self.path(name)?).await {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn stream(&self, name: &str, range: Range<u64>) -> Result<Option<ByteStream>, Report> {
+        let mut file = match fs::File::open(self.path(name)?).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        file.seek(SeekFrom::Start(range.start)).await?;
+
+        Ok(Some(read_chunks(file.take(range.end - range.start))))
+    }
+}
+
+/// S3-compatible object storage using path-style requests signed with AWS Signature Version 4,
+/// which works with AWS as well as self-hosted servers such as MinIO.
+pub struct S3Storage {
+    client: reqwest::Client,
+    endpoint: Url,
+    bucket: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+}
+
+impl S3Storage {
+    pub fn new(config: &S3Config) -> Result<Self, Report> {
+        Ok(Self {
+            client: reqwest::Client::new(),
+            endpoint: Url::parse(&config.endpoint)?,
+            bucket: config.bucket.clone(),
+            region: config.region.clone(),
+            access_key: config.access_key.clone(),
+            secret_key: config.secret_key.clone(),
+        })
+    }
+
+    async fn request(
+        &self,
+        method: Method,
+        name: Option<&str>,
+        query: &[(&str, &str)],
+        body: Vec<u8>,
+    ) -> Result<reqwest::Response, Report> {
+        Ok(self.signed(method, name, query, body)?.send().await?)
+    }
+
+    /// Builds a signed request, headers added to it afterwards are sent unsigned.
+    fn signed(
+        &self,
+        method: Method,
+        name: Option<&str>,
+        query: &[(&str, &str)],
+        body: Vec<u8>,
+    ) -> Result<reqwest::RequestBuilder, Report> {
+        let path = match name {
+            Some(name) => format!("/{}/{}", uri_encode(&self.bucket), uri_encode(name)),
+            None => format!("/{}", uri_encode(&self.bucket)),
+        };
+
+        let mut url = self.endpoint.join(&path)?;
+        let mut query: Vec<_> = query
+            .iter()
+            .map(|(k, v)| (uri_encode(k), uri_encode(v)))
+            .collect();
+        query.sort();
+        let query = query
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<_>>()
+            .join("&");
+        if !query.is_empty() {
+            url.set_query(Some(&query));
+        }
+
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+            None => url.host_str().unwrap_or_default().to_owned(),
+        };
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let payload_hash = hex::encode(Sha256::digest(&body));
+
+        let canonical_request = format!(
+            "{}\n{}\n{}\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method,
+            path,
+            query,
+            host,
+            payload_hash,
+            amz_date,
+            SIGNED_HEADERS,
+            payload_hash,
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes())),
+        );
+
+        let key = [date.as_str(), self.region.as_str(), "s3", "aws4_request"]
+            .iter()
+            .fold(format!("AWS4{}", self.secret_key).into_bytes(), |key, x| {
+                hmac_sha256(&key, x.as_bytes())
+            });
+        let signature = hex::encode(hmac_sha256(&key, string_to_sign.as_bytes()));
+
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key, scope, SIGNED_HEADERS, signature,
+        );
+
+        Ok(self
+            .client
+            .request(method, url)
+            .header("x-amz-content-sha256", payload_hash)
+            .header("x-amz-date", amz_date)
+            .header("authorization", authorization)
+            .body(body))
+    }
+}
+
+const SIGNED_HEADERS: &str = "host;x-amz-content-sha256;x-amz-date";
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC can take a key of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn uri_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    async fn put(&self, name: &str, bytes: &[u8]) -> Result<(), Report> {
+        info!("uploading {} with length {} ...", name, bytes.len());
+
+        let response = self
+            .request(Method::PUT, Some(name), &[], bytes.to_vec())
+            .await?;
+        response.error_for_status()?;
+        Ok(())
+    }
+
+    async fn get(&self, name: &str) -> Result<Option<Vec<u8>>, Report> {
+        let response = self.request(Method::GET, Some(name), &[], vec![]).await?;
+
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            _ => Ok(Some(response.error_for_status()?.bytes().await?.to_vec())),
+        }
+    }
+
+    async fn exists(&self, name: &str) -> Result<bool, Report> {
+        let response = self.request(Method::HEAD, Some(name), &[], vec![]).await?;
+
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(false),
+            _ => response.error_for_status().map(|_| true).map_err(Report::from),
+        }
+    }
+
+    async fn delete(&self, name: &str) -> Result<(), Report> {
+        let response = self
+            .request(Method::DELETE, Some(name), &[], vec![])
+            .await?;
+        response.error_for_status()?;
+        Ok(())
+    }
+
+    // only the first 1000 keys are returned, plenty for the mocker
+    async fn list(&self) -> Result<Vec<String>, Report> {
+        let response = self
+            .request(Method::GET, None, &[("list-type", "2")], vec![])
+            .await?;
+        let body = response.error_for_status()?.text().await?;
+
+        let re = Regex::new(r"<Key>([^<]*)</Key>")?;
+        Ok(re
+            .captures_iter(&body)
+            .map(|x| x[1].to_owned())
+            .collect())
+    }
+
+    async fn size(&self, name: &str) -> Result<Option<u64>, Report> {
+        let response = self.request(Method::HEAD, Some(name), &[], vec![]).await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        // `content_length` describes the empty body of the HEAD response, not the object
+        let response = response.error_for_status()?;
+        let size = response
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| x.parse().ok())
+            .ok_or_else(|| eyre!("no size for {}", name))?;
+        Ok(Some(size))
+    }
+
+    async fn stream(&self, name: &str, range: Range<u64>) -> Result<Option<ByteStream>, Report> {
+        // a range can't be empty, so neither can the request for one
+        if range.is_empty() {
+            return Ok(Some(stream::empty().boxed()));
+        }
+
+        let response = self
+            .signed(Method::GET, Some(name), &[], vec![])?
+            .header(header::RANGE, format!("bytes={}-{}", range.start, range.end - 1))
+            .send()
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        let response = response.error_for_status()?;
+        let chunks = stream::try_unfold(response, |mut response| async move {
+            let chunk = response
+                .chunk()
+                .await
+                .map_err(|e| io::Error::new(ErrorKind::Other, e))?;
+            Ok(chunk.map(|chunk| (chunk, response)))
+        });
+        Ok(Some(chunks.boxed()))
+    }
+}
+
+#[cfg(test)]
+async fn read(stream: Option<ByteStream>) -> Option<Vec<u8>> {
+    let chunks: Vec<_> = stream?.collect().await;
+    Some(chunks.into_iter().flat_map(|x| x.unwrap()).collect())
+}
+
+#[tokio::test]
+async fn test_local_storage() -> Result<(), Report> {
+    let root = crate::utils::helpers::test_dir("local-storage");
+    let storage = LocalStorage::new(&root)?;
+
+    storage.put("foo.png", b"foo").await?;
+    assert_eq!(storage.get("foo.png").await?, Some(b"foo".to_vec()));
+    assert!(storage.exists("foo.png").await?);
+    assert!(storage.list().await?.contains(&"foo.png".to_owned()));
+
+    storage.delete("foo.png").await?;
+    assert_eq!(storage.get("foo.png").await?, None);
+    assert!(!storage.exists("foo.png").await?);
+    assert!(storage.put("../foo.png", b"foo").await.is_err());
+
+    let source = crate::utils::helpers::test_dir("local-storage-source");
+    std::fs::write(&source, b"bar")?;
+    storage.put_file("bar.png", &source).await?;
+    assert_eq!(storage.get("bar.png").await?, Some(b"bar".to_vec()));
+    assert_eq!(storage.size("bar.png").await?, Some(3));
+    assert_eq!(read(storage.stream("bar.png", 1..3).await?).await, Some(b"ar".to_vec()));
+    assert_eq!(storage.size("foo.png").await?, None);
+    assert!(storage.stream("foo.png", 0..3).await?.is_none());
+
+    std::fs::remove_file(source)?;
+    std::fs::remove_dir_all(root)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_s3_storage() -> Result<(), Report> {
+    use axum::{
+        body::Bytes,
+        extract::{Path, State},
+        http::{HeaderMap, StatusCode},
+        routing::get,
+        Router,
+    };
+    use std::collections::HashMap;
+    use tokio::sync::Mutex;
+
+    type Objects = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+
+    // bare-bones stand-in for an S3-compatible server
+    fn signed(headers: &HeaderMap) -> bool {
+        headers
+            .get("authorization")
+            .and_then(|x| x.to_str().ok())
+            .map(|x| x.starts_with("AWS4-HMAC-SHA256 Credential=minio/"))
+            .unwrap_or(false)
+    }
+
+    let objects = Objects::default();
+    let app = Router::new()
+        .route(
+            "/:bucket",
+            get(|State(objects): State<Objects>, headers: HeaderMap| async move {
+                if !signed(&headers) {
+                    return Err(StatusCode::FORBIDDEN);
+                }
+                let keys: String = objects
+                    .lock()
+                    .await
+                    .keys()
+                    .map(|x| format!("<Contents><Key>{}</Key></Contents>", x))
+                    .collect();
+                Ok(format!("<ListBucketResult>{}</ListBucketResult>", keys))
+            }),
+        )
+        .route(
+            "/:bucket/:key",
+            get(
+                |State(objects): State<Objects>,
+                 Path((_, key)): Path<(String, String)>,
+                 headers: HeaderMap| async move {
+                    let object = objects.lock().await.get(&key).cloned();
+                    let object = object.ok_or(StatusCode::NOT_FOUND)?;
+                    // only the `bytes=start-end` form the client sends
+                    let range = headers
+                        .get("range")
+                        .and_then(|x| x.to_str().ok()?.strip_prefix("bytes=")?.split_once('-'))
+                        .map(|(start, end)| (start.parse().unwrap(), end.parse().unwrap()));
+                    match range {
+                        Some((start, end)) => {
+                            Ok((StatusCode::PARTIAL_CONTENT, object[start..=end].to_vec()))
+                        }
+                        None => Ok::<_, StatusCode>((StatusCode::OK, object)),
+                    }
+                },
+            )
+            .put(
+                |State(objects): State<Objects>,
+                 Path((_, key)): Path<(String, String)>,
+                 headers: HeaderMap,
+                 body: Bytes| async move {
+                    if !signed(&headers) {
+                        return StatusCode::FORBIDDEN;
+                    }
+                    objects.lock().await.insert(key, body.to_vec());
+                    StatusCode::OK
+                },
+            )
+            .delete(
+                |State(objects): State<Objects>, Path((_, key)): Path<(String, String)>| async move {
+                    objects.lock().await.remove(&key);
+                    StatusCode::NO_CONTENT
+                },
+            ),
+        )
+        .with_state(objects);
+
+    let server = axum::Server::bind(&"127.0.0.1:0".parse()?).serve(app.into_make_service());
+    let addr = server.local_addr();
+    tokio::spawn(server);
+
+    let storage = S3Storage::new(&S3Config {
+        endpoint: format!("http://{}", addr),
+        bucket: "tokichan".to_owned(),
+        region: "us-east-1".to_owned(),
+        access_key: "minio".to_owned(),
+        secret_key: "minio123".to_owned(),
+    })?;
+
+    storage.put("foo.png", b"foo").await?;
+    assert_eq!(storage.get("foo.png").await?, Some(b"foo".to_vec()));
+    assert!(storage.exists("foo.png").await?);
+    assert_eq!(storage.list().await?, vec!["foo.png".to_owned()]);
+    assert_eq!(storage.size("foo.png").await?, Some(3));
+    assert_eq!(read(storage.stream("foo.png", 1..3).await?).await, Some(b"oo".to_vec()));
+
+    storage.delete("foo.png").await?;
+    assert_eq!(storage.get("foo.png").await?, None);
+    assert!(!storage.exists("foo.png").await?);
+    assert_eq!(storage.size("foo.png").await?, None);
+
+    Ok(())
+}
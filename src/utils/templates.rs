@@ -2,7 +2,7 @@
 
 
 
-use super::data::{Board, Credentials, Post};
+use super::data::{BannedFile, Board, Credentials, Post};
 use askama::Template;
 use axum::{
     http::{StatusCode},
@@ -70,8 +70,17 @@ pub struct HomeTemplate {
 pub struct BoardTemplate {
     pub base: BaseTemplate,
     pub board: String,
+    pub current: Option<Board>,
     pub posts: Vec<Post>,
     pub input: Input,
+    pub page: i64,
+    pub pages: Vec<i64>,
+}
+
+impl BoardTemplate {
+    pub fn is_current(&self, page: &i64) -> bool {
+        *page == self.page
+    }
 }
 
 #[derive(Template, FromRow)]
@@ -79,10 +88,30 @@ pub struct BoardTemplate {
 pub struct ThreadTemplate {
     pub base: BaseTemplate,
     pub board: String,
+    pub current: Board,
     pub post: Post,
     pub children: Option<Vec<Post>>,
     pub input: Input,
     pub invalid_captcha: bool,
+    /// Links to a text question next to the captcha image when any are configured.
+    pub captcha_questions: bool,
+    pub archived: bool,
+}
+
+#[derive(Template, FromRow)]
+#[template(path = "archive.page.html")]
+pub struct ArchiveTemplate {
+    pub base: BaseTemplate,
+    pub board: String,
+    pub posts: Vec<Post>,
+    pub page: i64,
+    pub pages: Vec<i64>,
+}
+
+impl ArchiveTemplate {
+    pub fn is_current(&self, page: &i64) -> bool {
+        *page == self.page
+    }
 }
 
 #[derive(Template, FromRow)]
@@ -90,6 +119,7 @@ pub struct ThreadTemplate {
 pub struct ModTemplate {
     pub base: BaseTemplate,
     pub credentials: Credentials,
+    pub banned: Vec<BannedFile>,
 }
 
 #[derive(Template, FromRow)]
@@ -107,5 +137,6 @@ pub struct Input {
     pub body: String,
     pub parent: Option<i32>,
     pub captcha: String,
-    pub files: Option<Vec<String>>,
+    /// `nonce:counter` of a solved `PowChallenge`, used in place of the captcha.
+    pub pow: String,
 }
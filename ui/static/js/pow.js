@@ -0,0 +1,38 @@
+// Looks for a counter so that SHA-256("nonce:counter") starts with `difficulty` zero bits,
+// the server accepts "nonce:counter" in the pow field in place of the captcha.
+function leadingZeroBits(hash) {
+  let bits = 0;
+  for (const byte of hash) {
+    if (byte !== 0) {
+      return bits + Math.clz32(byte) - 24;
+    }
+    bits += 8;
+  }
+  return bits;
+}
+
+async function solve(button) {
+  const status = document.getElementById("pow-status");
+  const response = await fetch(`/.toki/pow/${button.dataset.board}`);
+  if (!response.ok) {
+    status.textContent = "not available on this board, solve the captcha instead";
+    return;
+  }
+
+  const { nonce, difficulty } = await response.json();
+  const encoder = new TextEncoder();
+  button.disabled = true;
+  status.textContent = "working...";
+
+  for (let counter = 0; ; counter++) {
+    const digest = await crypto.subtle.digest("SHA-256", encoder.encode(`${nonce}:${counter}`));
+    if (leadingZeroBits(new Uint8Array(digest)) >= difficulty) {
+      document.getElementById("pow").value = `${nonce}:${counter}`;
+      status.textContent = "done, you can post without solving the captcha";
+      return;
+    }
+  }
+}
+
+const button = document.getElementById("pow-solve");
+button.addEventListener("click", () => solve(button));